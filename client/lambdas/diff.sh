@@ -0,0 +1,7 @@
+#!/bin/env bash
+
+set -eu
+
+API=${API:-127.0.0.1:3000}
+
+curl -sS -L -X GET "$API"/lambdas/"$1"/diff -G --data from="$2" ${3:+--data to="$3"}
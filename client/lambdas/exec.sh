@@ -8,5 +8,5 @@ LAMBDA=$1
 SANDBOX=$2
 shift 2
 
-curl -s -L -H "Transfer-Encoding: chunked" -H 'Content-Type: text' -X POST "$API"/lambdas/"$LAMBDA"/exec -T - -G --data status=true --data sandbox="$SANDBOX" --data-urlencode "args=$*"
+curl -s -L -H "Transfer-Encoding: chunked" -H "TE: trailers" -H "Content-Type: ${CONTENT_TYPE:-text/plain}" -H "Accept: ${ACCEPT:-*/*}" -X POST "$API"/lambdas/"$LAMBDA"/exec -T - -G --data sandbox="$SANDBOX" --data-urlencode "args=$*"
 
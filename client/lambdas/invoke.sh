@@ -0,0 +1,11 @@
+#!/bin/env bash
+
+set -eu
+
+API=${API:-127.0.0.1:3000}
+
+LAMBDA=$1
+SANDBOX=$2
+shift 2
+
+curl -sS -L -H "Content-Type: ${CONTENT_TYPE:-text/plain}" -H "Accept: ${ACCEPT:-*/*}" -X POST "$API"/lambdas/"$LAMBDA"/invocations --data-binary @- -G --data sandbox="$SANDBOX" --data-urlencode "args=$*" | jq -r .id
@@ -0,0 +1,7 @@
+#!/bin/env bash
+
+set -eu
+
+API=${API:-127.0.0.1:3000}
+
+curl -sS -L -H 'Content-Type: application/merge-patch+json' -X PATCH "$API"/lambdas/"$1" --data @/dev/stdin
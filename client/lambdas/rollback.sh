@@ -0,0 +1,7 @@
+#!/bin/env bash
+
+set -eu
+
+API=${API:-127.0.0.1:3000}
+
+curl -sS -L -X POST "$API"/lambdas/"$1"/rollback -G --data to="$2"
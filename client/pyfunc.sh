@@ -0,0 +1,17 @@
+#!/bin/env bash
+
+set -eu
+
+HERE="$(cd "$(dirname "${BASH_SOURCE[0]:-$0}")" && pwd)"
+
+echo "[+] Install lambda"
+
+code='
+def add(a, b):
+    return {"sum": a + b}
+'
+
+jq -n --arg name "add" --argjson "py" "$(jq -n --arg pycode "$code" --arg entrypoint "add" '$ARGS.named')" '$ARGS.named' | "$HERE"/lambdas/put.sh
+
+echo '{"a": 1, "b": 2}' | "$HERE"/lambdas/exec.sh "add" "bwrap"
+echo
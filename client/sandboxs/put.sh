@@ -0,0 +1,7 @@
+#!/bin/env bash
+
+set -eu
+
+API=${API:-127.0.0.1:3000}
+
+curl -sS -L -H 'Content-Type: application/json' -X PUT "$API"/sandboxs --data @/dev/stdin
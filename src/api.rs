@@ -1,36 +1,63 @@
-use crate::{error::HttpErr, pagination::Pagination};
+use crate::{
+    error::HttpErr,
+    format::{Framer, Framing, InputFormat, OutputFormat, Stream},
+    history::{self, History, Version},
+    invocation::{self, Event, Kill, Options as InvocationOptions},
+    job::{self, Job},
+    pagination::Pagination,
+    process::Exit,
+    store::{self, StoreKind, Trait as StoreTrait},
+};
 use anyhow::Result;
 use axum::{
     body::{Body, Bytes},
     extract::{Path, Query, Request, State},
-    http::StatusCode,
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
     response::{IntoResponse, Response, Result as HttpResult},
     Json,
 };
-use axum_extra::body::AsyncReadBody;
-use futures::TryStreamExt;
+use futures::{StreamExt, TryStreamExt};
+use http_body::Frame;
+use http_body_util::{LengthLimitError, StreamBody};
+use log::{info, warn};
 use serde::Deserialize;
+use serde_json::{json, Value};
+use std::error::Error as _;
+use std::io::Cursor;
+use std::path::PathBuf;
 use std::process::Stdio;
+use std::time::Instant;
 use std::{
     collections::HashMap,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
 };
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader},
-    select,
-    sync::mpsc,
+    io::AsyncRead,
+    sync::{mpsc, Mutex as AsyncMutex, OwnedMutexGuard, Semaphore},
+    time::sleep,
 };
 use tokio_stream::wrappers::ReceiverStream;
 use tokio_util::io::StreamReader;
+use uuid::Uuid;
 
 pub struct AppState {
     /// Lambdas container
-    pub lambdas: HashMap<String, Arc<LambdaApp>>,
+    pub lambdas: HashMap<String, History>,
     /// Sandboxes container
     pub sandboxs: HashMap<String, Arc<Sandbox>>,
+    /// Asynchronous invocations by ID
+    pub jobs: HashMap<String, Arc<Mutex<Job>>>,
+    /// Slots of the asynchronous invocations running at once
+    pub job_slots: Arc<Semaphore>,
+    /// Storage the lambdas and sandboxes are written through
+    pub store: Arc<StoreKind>,
+    /// Held while the registry changes, so it is stored in the order of the changes
+    pub registry_writer: Arc<AsyncMutex<()>>,
+    /// Directory the paths of the sandboxes given by clients must be under
+    pub sandbox_root: PathBuf,
 }
-use crate::lambda_app::{LambdaAppKind as LambdaApp, Trait as LambdaTrait};
-use crate::sandbox::SandboxKind as Sandbox;
+use crate::lambda_app::{Lambda, LambdaAppKind, Trait as LambdaTrait};
+use crate::sandbox::Sandbox;
 
 pub type AppStateWrapper = Arc<RwLock<AppState>>;
 pub type HttpResponse = HttpResult<Response<Body>, HttpErr>;
@@ -47,6 +74,26 @@ fn lock_state_write(state: &AppStateWrapper) -> Result<std::sync::RwLockWriteGua
     state.write().map_err(move |e| anyhow::anyhow! { e.to_string() })
 }
 
+/// Wait for the turn to change the registry, the guard and the store it is written through
+async fn registry_writer(
+    s: &AppStateWrapper,
+) -> Result<(OwnedMutexGuard<()>, Arc<StoreKind>), HttpErr> {
+    let (writer, store) = {
+        let state = lock_state_read(s)?;
+        (Arc::clone(&state.registry_writer), Arc::clone(&state.store))
+    };
+    Ok((writer.lock_owned().await, store))
+}
+
+/// Write to the `store` off the async workers, without holding the state lock
+async fn write_store(
+    store: Arc<StoreKind>,
+    write: impl FnOnce(&StoreKind) -> Result<()> + Send + 'static,
+) -> Result<(), HttpErr> {
+    tokio::task::spawn_blocking(move || write(&store)).await.map_err(anyhow::Error::from)??;
+    Ok(())
+}
+
 /// Handler to return a paginated list of sandboxes
 pub async fn sandboxs_index(
     pagination: Option<Query<Pagination>>,
@@ -61,7 +108,73 @@ pub async fn sandboxs_index(
     Ok(Json(sandboxs).into_response())
 }
 
-/// Handler to return a paginated list of lambda applications
+/// Structure to receive data for creating a new sandbox
+#[derive(Deserialize)]
+pub struct SandboxsInsert {
+    name: String,
+    #[serde(flatten)]
+    sandbox: Sandbox,
+}
+
+/// Handler to insert a new sandbox, or replace the one of the same name
+pub async fn sandboxs_insert(
+    State(s): State<AppStateWrapper>,
+    sandboxsinsert: Json<SandboxsInsert>,
+) -> HttpResponse {
+    let sandboxsinsert = sandboxsinsert.0;
+
+    let document = serde_json::to_value(&sandboxsinsert.sandbox).map_err(anyhow::Error::from)?;
+    // An empty name can't be addressed nor stored
+    if sandboxsinsert.name.is_empty() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+    }
+    let (_writing, store) = registry_writer(&s).await?;
+    // The server writes lambdas and mounts in these paths
+    let sandbox_root = lock_state_read(&s)?.sandbox_root.clone();
+    if let Err(e) = sandboxsinsert.sandbox.check_paths(&sandbox_root) {
+        warn!("Refused sandbox {}: {e}", sandboxsinsert.name);
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+    }
+    let key = sandboxsinsert.name.clone();
+    write_store(store, move |store| store.save(store::SANDBOXS, &key, &document)).await?;
+    let sandbox = Arc::new(sandboxsinsert.sandbox);
+    let replaced = lock_state_write(&s)?.sandboxs.insert(sandboxsinsert.name, sandbox);
+
+    Ok(match replaced {
+        Some(_) => StatusCode::OK,
+        None => StatusCode::CREATED,
+    }
+    .into_response())
+}
+
+/// Handler to retrieve a sandbox by name
+pub async fn sandbox_get(
+    Path(name): Path<String>,
+    State(s): State<AppStateWrapper>,
+) -> HttpResponse {
+    let state = lock_state_read(&s)?;
+    let sandbox = state.sandboxs.get(&name).ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(sandbox).into_response())
+}
+
+/// Handler to delete a sandbox by name
+pub async fn sandbox_delete(
+    Path(name): Path<String>,
+    State(s): State<AppStateWrapper>,
+) -> HttpResponse {
+    let (_writing, store) = registry_writer(&s).await?;
+    if !lock_state_read(&s)?.sandboxs.contains_key(&name) {
+        return Err(StatusCode::NOT_FOUND.into());
+    }
+    let key = name.clone();
+    write_store(store, move |store| store.delete(store::SANDBOXS, &key)).await?;
+    let _ = lock_state_write(&s)?.sandboxs.remove(&name);
+
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Handler to return a paginated list of lambda applications, at their latest version
 pub async fn lambdas_index(
     pagination: Option<Query<Pagination>>,
     State(s): State<AppStateWrapper>,
@@ -69,8 +182,13 @@ pub async fn lambdas_index(
     let Query(pagination) = pagination.unwrap_or_default();
 
     let state = lock_state_read(&s)?;
-    let lambdas: HashMap<_, _> =
-        state.lambdas.iter().skip(pagination.offset).take(pagination.limit).collect();
+    let lambdas: HashMap<_, _> = state
+        .lambdas
+        .iter()
+        .filter_map(|(name, history)| Some((name, &history.latest()?.lambda)))
+        .skip(pagination.offset)
+        .take(pagination.limit)
+        .collect();
 
     Ok(Json(lambdas).into_response())
 }
@@ -80,55 +198,487 @@ pub async fn lambdas_index(
 pub struct LambdasInsert {
     name: String,
     #[serde(flatten)]
-    app: LambdaApp,
+    app: Lambda,
 }
 
-/// Handler to insert a new lambda application
+/// Handler to publish a new version of a lambda application,
+/// `If-Match` and `If-None-Match` are checked against its latest version
 pub async fn lambdas_insert(
     State(s): State<AppStateWrapper>,
+    headers: HeaderMap,
     lambdasinsert: Json<LambdasInsert>,
 ) -> HttpResponse {
     let lambdasinsert = lambdasinsert.0;
+    // `@` separates the name from a version in references, an empty name can't be addressed
+    if lambdasinsert.name.is_empty()
+        || lambdasinsert.name.contains('@')
+        || !lambdasinsert.app.is_valid()
+    {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+    }
 
-    let mut state = lock_state_write(&s)?;
-    let _ = state.lambdas.insert(lambdasinsert.name, Arc::new(lambdasinsert.app));
+    let app = Arc::new(lambdasinsert.app);
+    let etag = app.etag();
+    let version = update_history(&s, &lambdasinsert.name, true, |history| {
+        check_preconditions(&headers, history.latest().map(|v| v.lambda.etag()).as_deref())?;
+        Ok(history.publish(app))
+    })
+    .await?;
 
-    Ok(StatusCode::CREATED.into_response())
+    let body = json!({ "name": lambdasinsert.name, "version": version });
+    Ok((StatusCode::CREATED, [(header::ETAG, etag)], Json(body)).into_response())
 }
 
-/// Handler to retrieve a lambda application by name
-pub async fn lambda_get(
+/// Handler to publish a new version of a lambda application changed by a JSON Merge Patch
+/// (RFC 7396) of its latest version, `If-Match` is checked against the latter
+pub async fn lambda_patch(
     Path(name): Path<String>,
     State(s): State<AppStateWrapper>,
+    headers: HeaderMap,
+    patch: Bytes,
+) -> HttpResponse {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    let media_type = content_type.and_then(|t| t.split(';').next()).map(str::trim);
+    if !matches!(media_type, Some("application/merge-patch+json" | "application/json")) {
+        return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE.into());
+    }
+    let patch: Value = serde_json::from_slice(&patch).map_err(|_e| StatusCode::BAD_REQUEST)?;
+
+    let (version, etag) = update_history(&s, &name, false, |history| {
+        let latest = history.latest().ok_or(StatusCode::NOT_FOUND)?;
+        let latest_etag = latest.lambda.etag();
+        check_preconditions(&headers, Some(&latest_etag))?;
+        let lambda = patched(&latest.lambda, &patch)?;
+        let etag = lambda.etag();
+        // Nothing changed, the latest version stands
+        if etag == latest_etag {
+            return Ok((latest.number, etag));
+        }
+        Ok((history.publish(Arc::new(lambda)), etag))
+    })
+    .await?;
+
+    let body = json!({ "name": name, "version": version });
+    Ok(([(header::ETAG, etag)], Json(body)).into_response())
+}
+
+/// The `lambda` changed by a JSON Merge Patch, which must leave it a single kind of app
+fn patched(lambda: &Lambda, patch: &Value) -> Result<Lambda, HttpErr> {
+    let mut document = serde_json::to_value(lambda).map_err(anyhow::Error::from)?;
+    merge_patch(&mut document, patch);
+    let kinds = LambdaAppKind::KEYS.iter().filter(|&&kind| document.get(kind).is_some()).count();
+    if kinds != 1 {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+    }
+    let patched: Lambda =
+        serde_json::from_value(document).map_err(|_e| StatusCode::UNPROCESSABLE_ENTITY)?;
+    if !patched.is_valid() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+    }
+    Ok(patched)
+}
+
+/// Apply a JSON Merge Patch to `target`: objects are merged recursively,
+/// `null` removes a member and any other value replaces it
+fn merge_patch(target: &mut Value, patch: &Value) {
+    let Value::Object(members) = patch else {
+        target.clone_from(patch);
+        return;
+    };
+    if !target.is_object() {
+        *target = Value::Object(serde_json::Map::new());
+    }
+    if let Value::Object(fields) = target {
+        for (key, value) in members {
+            if value.is_null() {
+                let _ = fields.remove(key);
+            } else {
+                merge_patch(fields.entry(key.as_str()).or_insert(Value::Null), value);
+            }
+        }
+    }
+}
+
+/// Handler to retrieve a lambda application by reference, `name`, `name@3` or `name@alias`,
+/// with its entity tag
+pub async fn lambda_get(
+    Path(reference): Path<String>,
+    State(s): State<AppStateWrapper>,
+    headers: HeaderMap,
 ) -> HttpResponse {
     let state = lock_state_read(&s)?;
-    let lambda = state.lambdas.get(&name).ok_or(StatusCode::NOT_FOUND)?;
+    let published = resolve(&state.lambdas, &reference).ok_or(StatusCode::NOT_FOUND)?;
+    let etag = published.lambda.etag();
+    let cached = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|tags| etag_matches(tags, Some(&etag), false));
+    if cached {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
 
-    Ok(Json(lambda).into_response())
+    Ok(([(header::ETAG, etag)], Json(&published.lambda)).into_response())
 }
 
-/// Handler to delete a lambda application by name
+/// Handler to delete a lambda application by name, with all its versions,
+/// `If-Match` is checked against its latest version
 pub async fn lambda_delete(
     Path(name): Path<String>,
     State(s): State<AppStateWrapper>,
+    headers: HeaderMap,
+) -> HttpResponse {
+    let (_writing, store) = registry_writer(&s).await?;
+    let etag = {
+        let state = lock_state_read(&s)?;
+        let history = state.lambdas.get(&name).ok_or(StatusCode::NOT_FOUND)?;
+        history.latest().map(|v| v.lambda.etag())
+    };
+    check_preconditions(&headers, etag.as_deref())?;
+    let key = name.clone();
+    write_store(store, move |store| store.delete(store::LAMBDAS, &key)).await?;
+    let _ = lock_state_write(&s)?.lambdas.remove(&name);
+
+    Ok(StatusCode::OK.into_response())
+}
+
+/// Handler to list the versions of a lambda application with their aliases
+pub async fn lambda_versions(
+    Path(name): Path<String>,
+    State(s): State<AppStateWrapper>,
+) -> HttpResponse {
+    let state = lock_state_read(&s)?;
+    let history = state.lambdas.get(&name).ok_or(StatusCode::NOT_FOUND)?;
+    let versions: Vec<_> = history
+        .versions()
+        .iter()
+        .map(|v| {
+            let aliases: Vec<_> =
+                history.aliases().iter().filter(|(_, n)| **n == v.number).map(|(a, _)| a).collect();
+            json!({ "version": v.number, "published": v.published, "aliases": aliases })
+        })
+        .collect();
+
+    Ok(Json(versions).into_response())
+}
+
+/// Rollback parameters
+#[derive(Deserialize)]
+pub struct RollbackParams {
+    /// Version number or alias to roll back to
+    to: String,
+}
+
+/// Handler to roll a lambda application back to one of its versions,
+/// published again as the latest version, `If-Match` is checked against the latter
+pub async fn lambda_rollback(
+    Path(name): Path<String>,
+    Query(params): Query<RollbackParams>,
+    State(s): State<AppStateWrapper>,
+    headers: HeaderMap,
+) -> HttpResponse {
+    let version = update_history(&s, &name, false, |history| {
+        check_preconditions(&headers, history.latest().map(|v| v.lambda.etag()).as_deref())?;
+        let version = history.resolve(Some(&params.to)).ok_or(StatusCode::NOT_FOUND)?;
+        let lambda = Arc::clone(&version.lambda);
+        Ok(history.publish(lambda))
+    })
+    .await?;
+
+    let body = json!({ "name": name, "version": version });
+    Ok((StatusCode::CREATED, Json(body)).into_response())
+}
+
+/// Diff parameters
+#[derive(Deserialize)]
+pub struct DiffParams {
+    /// Version number or alias diffed from
+    from: String,
+    /// Version number or alias diffed to, the latest version by default
+    to: Option<String>,
+}
+
+/// Handler to return the unified diff of the code and the metadata of two versions of a lambda
+pub async fn lambda_diff(
+    Path(name): Path<String>,
+    Query(params): Query<DiffParams>,
+    State(s): State<AppStateWrapper>,
+) -> HttpResponse {
+    let state = lock_state_read(&s)?;
+    let history = state.lambdas.get(&name).ok_or(StatusCode::NOT_FOUND)?;
+    let from = history.resolve(Some(&params.from)).ok_or(StatusCode::NOT_FOUND)?;
+    let to = history.resolve(params.to.as_deref()).ok_or(StatusCode::NOT_FOUND)?;
+
+    let diff = history::diff(&name, from, to);
+    Ok(([(header::CONTENT_TYPE, "text/x-diff; charset=utf-8")], diff).into_response())
+}
+
+/// Structure to receive the version an alias points to
+#[derive(Deserialize)]
+pub struct AliasPut {
+    version: u32,
+}
+
+/// Handler to point an alias of a lambda application to one of its versions,
+/// `If-Match` is checked against its latest version
+pub async fn alias_put(
+    Path((name, alias)): Path<(String, String)>,
+    State(s): State<AppStateWrapper>,
+    headers: HeaderMap,
+    aliasput: Json<AliasPut>,
 ) -> HttpResponse {
-    let mut state = lock_state_write(&s)?;
-    let _ = state.lambdas.remove(&name).ok_or(StatusCode::NOT_FOUND)?;
+    if !history::valid_alias(&alias) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY.into());
+    }
+    update_history(&s, &name, false, |history| {
+        check_preconditions(&headers, history.latest().map(|v| v.lambda.etag()).as_deref())?;
+        history.set_alias(alias, aliasput.version).then_some(()).ok_or(StatusCode::NOT_FOUND.into())
+    })
+    .await?;
+    Ok(StatusCode::OK.into_response())
+}
 
+/// Handler to delete an alias of a lambda application,
+/// `If-Match` is checked against its latest version
+pub async fn alias_delete(
+    Path((name, alias)): Path<(String, String)>,
+    State(s): State<AppStateWrapper>,
+    headers: HeaderMap,
+) -> HttpResponse {
+    update_history(&s, &name, false, |history| {
+        check_preconditions(&headers, history.latest().map(|v| v.lambda.etag()).as_deref())?;
+        history.remove_alias(&alias).then_some(()).ok_or(StatusCode::NOT_FOUND.into())
+    })
+    .await?;
     Ok(StatusCode::OK.into_response())
 }
 
+/// Published version of a lambda reference, `name`, `name@3` or `name@alias`
+fn resolve<'a>(lambdas: &'a HashMap<String, History>, reference: &str) -> Option<&'a Version> {
+    let (name, version) = history::parse_reference(reference);
+    lambdas.get(name)?.resolve(version)
+}
+
+/// Apply `update` to a copy of the history of a lambda, empty when `create` and it doesn't exist,
+/// and replace it once stored. Its versions share their lambdas, the copy is cheap
+async fn update_history<T>(
+    s: &AppStateWrapper,
+    name: &str,
+    create: bool,
+    update: impl FnOnce(&mut History) -> Result<T, HttpErr>,
+) -> Result<T, HttpErr> {
+    let (_writing, store) = registry_writer(s).await?;
+    let mut history = match lock_state_read(s)?.lambdas.get(name) {
+        Some(history) => history.clone(),
+        None if create => History::default(),
+        None => return Err(StatusCode::NOT_FOUND.into()),
+    };
+    let updated = update(&mut history)?;
+    let (stored, key) = (history.clone(), name.to_string());
+    write_store(store, move |store| {
+        store.save(store::LAMBDAS, &key, &serde_json::to_value(&stored)?)
+    })
+    .await?;
+    let _ = lock_state_write(s)?.lambdas.insert(name.to_string(), history);
+    Ok(updated)
+}
+
+/// Whether an `If-Match` or `If-None-Match` list of entity tags matches the current one,
+/// `*` matches any existing entity. The `strong` comparison of `If-Match` never matches
+/// a weak tag, the weak one of `If-None-Match` ignores the `W/` prefix
+fn etag_matches(tags: &str, current: Option<&str>, strong: bool) -> bool {
+    let Some(current) = current else {
+        return false;
+    };
+    tags.split(',').map(str::trim).any(|tag| match (tag.strip_prefix("W/"), strong) {
+        _ if tag == "*" => true,
+        (Some(_), true) => false,
+        (Some(weak), false) => weak == current,
+        (None, _) => tag == current,
+    })
+}
+
+/// Check the `If-Match` and `If-None-Match` preconditions of a change
+/// against the entity tag of the current entity, `None` when there is none yet
+fn check_preconditions(headers: &HeaderMap, current: Option<&str>) -> Result<(), HttpErr> {
+    let header = |key| headers.get(key).and_then(|v: &HeaderValue| v.to_str().ok());
+    let failed = match (header(header::IF_MATCH), header(header::IF_NONE_MATCH)) {
+        (Some(tags), _) => !etag_matches(tags, current, true),
+        (None, Some(tags)) => etag_matches(tags, current, false),
+        (None, None) => false,
+    };
+    match failed {
+        true => Err(StatusCode::PRECONDITION_FAILED.into()),
+        false => Ok(()),
+    }
+}
+
 /// Lambda execution parameters
 #[derive(Debug, Deserialize)]
 pub struct ExecParams {
     sandbox: String,
     args: String,
+    /// Timeout in seconds, overrides the lambda default
+    #[serde(default)]
+    timeout: Option<u64>,
+    /// Where the lambda stderr goes
+    #[serde(default)]
+    stderr: StderrMode,
+    /// Whether the response is streamed or sent once the lambda ended
+    #[serde(default)]
+    mode: ExecMode,
+    /// Deprecated, the exit status is also written at the end of a streamed output
+    /// as before the trailers
+    #[serde(default)]
     status: bool,
 }
 
 impl Default for ExecParams {
     fn default() -> Self {
-        Self { sandbox: "host".to_string(), args: String::new(), status: false }
+        Self {
+            sandbox: "host".to_string(),
+            args: String::new(),
+            timeout: None,
+            stderr: StderrMode::default(),
+            mode: ExecMode::default(),
+            status: false,
+        }
+    }
+}
+
+/// Destination of the lambda stderr
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum StderrMode {
+    /// In the response body, with stdout
+    #[default]
+    Body,
+    /// In the server logs, the body holds stdout only
+    Log,
+}
+
+/// How the exec response is sent
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExecMode {
+    /// Streamed while the lambda runs, always `200 OK`
+    #[default]
+    Stream,
+    /// Sent once the lambda ended, with a status telling whether it succeeded
+    Buffered,
+}
+
+/// A lambda invocation ready to be spawned
+struct Launch {
+    /// Lambda name
+    name: String,
+    /// Invoked lambda
+    lambda: Arc<Lambda>,
+    /// Sandbox to run it in
+    sandbox: Arc<Sandbox>,
+    /// Command line arguments
+    args: Vec<String>,
+    /// Format of a structured result
+    format: Option<OutputFormat>,
+    /// Environment telling the lambda its input and output formats
+    envs: Vec<(&'static str, &'static str)>,
+    /// Limits of the invocation
+    options: InvocationOptions,
+}
+
+impl Launch {
+    /// Check an invocation of the lambda `name` against the request `headers`,
+    /// a framed response gets JSON records by default
+    fn new(
+        s: &AppStateWrapper,
+        name: String,
+        params: &ExecParams,
+        headers: &HeaderMap,
+        framed: bool,
+    ) -> Result<Self, HttpErr> {
+        let header = |key| headers.get(key).and_then(|v: &HeaderValue| v.to_str().ok());
+        let content_length = header(header::CONTENT_LENGTH).and_then(|v| v.parse::<u64>().ok());
+
+        // Here we need to retrieve and drop the state lock
+        // because ReadLockGuard is !Send and so we cannot keep it across an await point
+        // (it would need to be locked and unlocked on the same thread during child wait() which tokio doesn't guarantee)
+        // Since our state uses Arc, clone is just a ptr copy
+        let (lambda, sandbox) = {
+            let state = lock_state_read(s)?;
+            (
+                Arc::clone(&resolve(&state.lambdas, &name).ok_or(StatusCode::NOT_FOUND)?.lambda),
+                Arc::clone(state.sandboxs.get(&params.sandbox).ok_or(StatusCode::NOT_FOUND)?),
+            )
+        };
+
+        let options = InvocationOptions {
+            timeout: params.timeout.or(lambda.timeout),
+            max_input: lambda.max_input_bytes,
+            // A buffered response holds the whole output, it is always capped
+            max_output: lambda.max_output_bytes.or(match params.mode {
+                ExecMode::Buffered => Some(MAX_BUFFERED_OUTPUT),
+                ExecMode::Stream => None,
+            }),
+            log_stderr: params.stderr == StderrMode::Log,
+            typed_output: lambda.app.structured_output(),
+        };
+        // Refuse announced oversized bodies before spawning, others are cut while streaming
+        if content_length.zip(options.max_input).is_some_and(|(len, max)| len > max) {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE.into());
+        }
+
+        // Structured results are serialized in the format asked by the client,
+        // framed ones default to JSON records
+        let format = match lambda.app.structured_output() {
+            true => Some(
+                OutputFormat::negotiate(header(header::ACCEPT))
+                    .or(framed.then_some(OutputFormat::JsonRecords))
+                    .ok_or(StatusCode::NOT_ACCEPTABLE)?,
+            ),
+            false => None,
+        };
+        // The body is parsed into a DataFrame according to its content type
+        let input_format = match lambda.app.dataframe_input() {
+            true => Some(
+                header(header::CONTENT_TYPE)
+                    .and_then(InputFormat::from_content_type)
+                    .ok_or(StatusCode::UNSUPPORTED_MEDIA_TYPE)?,
+            ),
+            false => None,
+        };
+        let envs = format
+            .map(|f| ("FREEITW_OUTPUT_FORMAT", f.as_str()))
+            .into_iter()
+            .chain(input_format.map(|f| ("FREEITW_INPUT_FORMAT", f.as_str())))
+            .collect();
+
+        let args = params.args.split_whitespace().map(String::from).collect();
+        Ok(Self { name, lambda, sandbox, args, format, envs, options })
+    }
+
+    /// Spawn the lambda with `input` as stdin
+    fn spawn<R>(&self, input: R) -> Result<mpsc::Receiver<std::io::Result<Event>>>
+    where
+        R: AsyncRead + Send + 'static,
+    {
+        let args = self.args.iter().map(String::as_str).collect::<Vec<_>>();
+        // The formats come last so the lambda environment can't change them
+        let envs = self
+            .lambda
+            .env
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .chain(self.envs.iter().copied())
+            .collect::<Vec<_>>();
+        let started = Instant::now();
+        let (child, scope) = self.lambda.app.spawn(
+            &*self.sandbox,
+            &args,
+            &envs,
+            Stdio::piped(),
+            Stdio::piped(),
+            Stdio::piped(),
+        )?;
+        Ok(invocation::run(self.name.clone(), child, scope, started, input, self.options)?)
     }
 }
 
@@ -141,98 +691,618 @@ pub async fn lambda_exec(
 ) -> HttpResponse {
     // Url query parameters
     let Query(params) = params.unwrap_or_default();
-    let sandbox = params.sandbox;
-    let args = params.args.split_whitespace().collect::<Vec<_>>();
-    let print_status = params.status;
+    let (parts, body) = req.into_parts();
 
-    // Convert the body into an `AsyncRead`.
-    let body = req.into_body().into_data_stream().map_err(std::io::Error::other);
-    let body_with_io_error =
-        body.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err));
-    let body_reader = StreamReader::new(body_with_io_error);
-
-    // Here we need to retrieve and drop the state lock
-    // because ReadLockGuard is !Send and so we cannot keep it across an await point
-    // (it would need to be locked and unlocked on the same thread during child wait() which tokio doesn't guarantee)
-    // Since our state uses Arc, clone is just a ptr copy
-    let (lambda, sandbox) = {
-        let state = lock_state_read(&s)?;
-        (
-            Arc::clone(state.lambdas.get(&name).ok_or(StatusCode::NOT_FOUND)?),
-            Arc::clone(state.sandboxs.get(&sandbox).ok_or(StatusCode::NOT_FOUND)?),
-        )
+    // Streams are framed when the client asks for it, buffered responses never are
+    let framing = match params.mode {
+        ExecMode::Stream => {
+            Framing::negotiate(parts.headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()))
+        }
+        ExecMode::Buffered => Framing::Merged,
     };
+    let launch = Launch::new(&s, name, &params, &parts.headers, framing.is_framed())?;
+
+    // Convert the body into an `AsyncRead`.
+    let body_reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
 
     // SPAWN THE CHILD PROCESS
-    let mut child =
-        lambda.spawn(&*sandbox, &args, Stdio::piped(), Stdio::piped(), Stdio::piped())?;
+    let mut events = launch.spawn(body_reader)?;
 
-    // setup streaming
-    let stdin = child.stdin.take().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    match params.mode {
+        ExecMode::Stream => {
+            // The headers wait for the content type a structured result reports
+            let (reported, early) = match launch.format {
+                Some(_) => reported_content_type(&mut events).await,
+                None => (None, Vec::new()),
+            };
+            let stdout_type = reported.or_else(|| launch.format.map(|_| JSON_TYPE.to_string()));
+            let response_type = framing.content_type(stdout_type.as_deref());
+            let framer = Framer::new(framing, stdout_type.as_deref());
+            let events = futures::stream::iter(early).chain(ReceiverStream::new(events));
+            Ok(streamed_response(events, framer, response_type, params.status))
+        }
+        ExecMode::Buffered => {
+            let failure =
+                launch.lambda.failure_status().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+            buffered_response(events, failure, launch.format.is_some()).await
+        }
+    }
+}
 
-    let stdout = BufReader::new(child.stdout.take().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?);
-    let stderr = BufReader::new(child.stderr.take().ok_or(StatusCode::INTERNAL_SERVER_ERROR)?);
+/// Output cap of buffered responses when the lambda sets none
+const MAX_BUFFERED_OUTPUT: u64 = 64 << 20;
 
-    let mut body_reader = Box::pin(body_reader);
-    let mut stdout = Box::pin(stdout);
-    let mut stderr = Box::pin(stderr);
-    let mut stdin_buf = vec![0_u8; 128];
-    let mut stdout_buf = vec![0_u8; 128];
-    let mut stderr_buf = vec![0_u8; 128];
+/// Last bytes of each output kept in the error of a buffered response
+const MAX_ERROR_OUTPUT: usize = 64 << 10;
 
-    let (tx, rx) = mpsc::channel::<Result<Bytes, HttpErr>>(4);
+/// Content type of structured results not reporting one, errors included
+const JSON_TYPE: &str = "application/json";
 
-    #[allow(clippy::let_underscore_future)]
-    let _ = tokio::spawn(async move {
-        let mut stdin_container = Some(stdin);
-        loop {
-            select! {
-                status = child.wait() => {
-                    let status = match status {
-                        Err(e) => return tx.send(Err(HttpErr::Io(e))).await.expect("channel to be alive"),
-                        Ok(status) => status,
-                    };
-                   if print_status {
-                       let () = tx.send(Ok(Bytes::from(format!("Exit status {status}")))).await.expect("channel to be alive");
-                   }
-                   break;
-               },
-               n = body_reader.read(&mut stdin_buf) => {
-                    let n = match n {
-                        Err(e) => return tx.send(Err(HttpErr::Io(e))).await.expect("channel to be alive"),
-                        Ok(n) => n,
-                    };
-                    if n == 0 {
-                        if let Some(child_stdin) = stdin_container.take() {
-                            // Drop stdin
-                            std::mem::drop(child_stdin);
-                        }
-                    }
-                    if let Some(child_stdin) = stdin_container.as_mut() {
-                        let _err = child_stdin.write_all(&stdin_buf[..n]).await.is_err();
-                    }
-               }
-               n = stdout.read(&mut stdout_buf) => {
-                    let n = match n {
-                        Err(e) => return tx.send(Err(HttpErr::Io(e))).await.expect("channel to be alive"),
-                        Ok(n) => n,
-                    };
-
-                   let b = stdout_buf[..n].to_vec();
-                   let () = tx.send(Ok(Bytes::from(b))).await.expect("channel to be alive");
-               }
-               n = stderr.read(&mut stderr_buf) => {
-                    let n = match n {
-                        Err(e) => return tx.send(Err(HttpErr::Io(e))).await.expect("channel to be alive"),
-                        Ok(n) => n,
-                    };
-                    let b = stderr_buf[..n].to_vec();
-                    let () = tx.send(Ok(Bytes::from(b))).await.expect("channel to be alive");
+/// Stderr bytes held back while waiting for the content type of a structured result
+const MAX_EARLY_OUTPUT: usize = 64 << 10;
+
+/// Wait for the content type reported by a structured result, which comes before its output.
+/// The events received meanwhile are given back, past `MAX_EARLY_OUTPUT` bytes of them
+/// the wait is over and the response starts with the default type
+async fn reported_content_type(
+    events: &mut mpsc::Receiver<std::io::Result<Event>>,
+) -> (Option<String>, Vec<std::io::Result<Event>>) {
+    let mut early = Vec::new();
+    let mut held = 0;
+    while let Some(event) = events.recv().await {
+        match event {
+            Ok(Event::ContentType(content_type)) => return (Some(content_type), early),
+            // Nothing is reported once the result started or the lambda ended
+            Ok(Event::Output(Stream::Stdout, _) | Event::Exit(_)) | Err(_) => {
+                early.push(event);
+                break;
+            }
+            Ok(Event::Output(Stream::Stderr, ref data)) => {
+                held += data.len();
+                early.push(event);
+                if held > MAX_EARLY_OUTPUT {
+                    break;
                 }
             }
+            event => early.push(event),
+        }
+    }
+    (None, early)
+}
+
+/// Handler to invoke a lambda function asynchronously, the response tells the job ID
+pub async fn lambda_invoke(
+    params: Option<Query<ExecParams>>,
+    Path(name): Path<String>,
+    State(s): State<AppStateWrapper>,
+    req: Request,
+) -> HttpResponse {
+    let Query(params) = params.unwrap_or_default();
+    let (parts, body) = req.into_parts();
+    let launch = Launch::new(&s, name, &params, &parts.headers, false)?;
+
+    // Nobody waits for the lambda, the whole body is read first and held until it starts
+    let input = read_body(body, launch.options.max_input.unwrap_or(job::MAX_INPUT)).await?;
+
+    let content_type = launch.format.map(|_| JSON_TYPE.to_string());
+    let (id, job, slots) = {
+        let mut state = lock_state_write(&s)?;
+        if state.jobs.len() >= job::MAX_JOBS {
+            // Make room by forgetting the job which ended first, unless all are pending
+            let oldest = state
+                .jobs
+                .iter()
+                .filter_map(|(id, job)| Some((job::lock(job).ended?, id.clone())))
+                .min();
+            let (_, oldest) = oldest.ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+            std::mem::drop(state.jobs.remove(&oldest));
+        }
+        let id = loop {
+            let id = Uuid::new_v4().to_string();
+            if !state.jobs.contains_key(&id) {
+                break id;
+            }
+        };
+        let job = Arc::new(Mutex::new(Job::new(id.clone(), launch.name.clone(), content_type)));
+        let _ = state.jobs.insert(id.clone(), Arc::clone(&job));
+        (id, job, Arc::clone(&state.job_slots))
+    };
+
+    let task_id = id.clone();
+    #[allow(clippy::let_underscore_future, reason = "the task is detached on purpose")]
+    let _ = tokio::spawn(async move {
+        // Queued until a slot is free, the semaphore is never closed
+        if let Ok(_slot) = slots.acquire_owned().await {
+            match launch.spawn(Cursor::new(input)) {
+                Err(e) => job::fail(&job, format!("{e:#}")),
+                Ok(events) => job::capture(&job, events).await,
+            }
+        }
+        info!("Lambda {} job {task_id} ended", launch.name);
+        sleep(job::RETENTION).await;
+        match lock_state_write(&s) {
+            Ok(mut state) => std::mem::drop(state.jobs.remove(&task_id)),
+            Err(e) => warn!("Failed to remove expired job {task_id}: {e}"),
         }
     });
 
-    Ok((StatusCode::OK, AsyncReadBody::new(StreamReader::new(ReceiverStream::new(rx))))
+    let location = format!("/invocations/{id}");
+    Ok((StatusCode::ACCEPTED, [(header::LOCATION, location)], Json(json!({ "id": id })))
         .into_response())
 }
+
+/// Read a whole request body, `413 Payload Too Large` past `max` bytes
+async fn read_body(body: Body, max: u64) -> Result<Bytes, HttpErr> {
+    let limit = usize::try_from(max).unwrap_or(usize::MAX);
+    axum::body::to_bytes(body, limit).await.map_err(|e| {
+        match e.source().and_then(|e| e.downcast_ref::<LengthLimitError>()) {
+            Some(_) => HttpErr::Status(StatusCode::PAYLOAD_TOO_LARGE),
+            None => HttpErr::Err(anyhow::anyhow!(e)),
+        }
+    })
+}
+
+/// Handler to retrieve the state of an asynchronous invocation
+pub async fn invocation_get(
+    Path(id): Path<String>,
+    State(s): State<AppStateWrapper>,
+) -> HttpResponse {
+    let job = Arc::clone(lock_state_read(&s)?.jobs.get(&id).ok_or(StatusCode::NOT_FOUND)?);
+    let job = job::lock(&job);
+    Ok(Json(&*job).into_response())
+}
+
+/// Output retrieval parameters
+#[derive(Debug, Deserialize, Default)]
+pub struct OutputParams {
+    /// Captured stream to return, stdout by default
+    #[serde(default)]
+    stream: Option<Stream>,
+}
+
+/// Handler to retrieve the output of an asynchronous invocation, captured so far
+pub async fn invocation_output(
+    params: Option<Query<OutputParams>>,
+    Path(id): Path<String>,
+    State(s): State<AppStateWrapper>,
+) -> HttpResponse {
+    let Query(params) = params.unwrap_or_default();
+    let job = Arc::clone(lock_state_read(&s)?.jobs.get(&id).ok_or(StatusCode::NOT_FOUND)?);
+    let job = job::lock(&job);
+    let stream = params.stream.unwrap_or(Stream::Stdout);
+    let content_type = match stream {
+        Stream::Stdout => job.content_type.as_deref().unwrap_or("application/octet-stream"),
+        Stream::Stderr => "text/plain; charset=utf-8",
+    };
+    Ok(([(header::CONTENT_TYPE, content_type)], job.output(stream).to_vec()).into_response())
+}
+
+/// A frame of the exec response body
+type BodyFrame = Result<Frame<Bytes>, HttpErr>;
+
+/// Names of the exit status trailers and buffered response headers,
+/// lowercase as `HeaderName` wants
+const EXIT_TRAILERS: [&str; 7] = [
+    "x-exit-code",
+    "x-exit-signal",
+    "x-killed",
+    "x-duration-ms",
+    "x-cpu-user-ms",
+    "x-cpu-system-ms",
+    "x-max-rss-kb",
+];
+
+/// Response streaming the invocation output, the exit status comes last in trailers
+/// and in the output too with `print_status`
+fn streamed_response(
+    events: impl futures::Stream<Item = std::io::Result<Event>> + Send + 'static,
+    mut framer: Framer,
+    content_type: Option<String>,
+    print_status: bool,
+) -> Response {
+    let mut killed = None;
+    let chunks = events.flat_map(move |event| {
+        futures::stream::iter(body_frames(&mut framer, &mut killed, event, print_status))
+    });
+    let body = Body::new(StreamBody::new(chunks));
+    let mut headers = HeaderMap::new();
+    if let Some(content_type) = content_type.and_then(|t| HeaderValue::from_str(&t).ok()) {
+        let _ = headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    if let Ok(trailer) = HeaderValue::from_str(&EXIT_TRAILERS.join(", ")) {
+        let _ = headers.insert(header::TRAILER, trailer);
+    }
+    (StatusCode::OK, headers, body).into_response()
+}
+
+/// Frames of the response body for an invocation event, `killed` keeps why the lambda
+/// was killed until its exit
+fn body_frames(
+    framer: &mut Framer,
+    killed: &mut Option<Kill>,
+    event: std::io::Result<Event>,
+    print_status: bool,
+) -> Vec<BodyFrame> {
+    let data = match event {
+        Err(e) => return vec![Err(HttpErr::Io(e))],
+        // Already in the response headers
+        Ok(Event::ContentType(_)) => Vec::new(),
+        Ok(Event::Output(stream, data)) => vec![framer.frame(stream, &data)],
+        Ok(Event::Killed(kill)) => {
+            *killed = Some(kill);
+            // Merged output would be corrupted, it is only told with the deprecated marker
+            match framer.is_framed() || print_status {
+                true => vec![framer.frame(Stream::Stderr, format!("\n{kill}").as_bytes())],
+                false => Vec::new(),
+            }
+        }
+        Ok(Event::Exit(exit)) => {
+            let mut status = serde_json::to_value(exit).unwrap_or_default();
+            if let Some(fields) = status.as_object_mut() {
+                let _ = fields.insert("killed".to_string(), json!(killed.map(Kill::as_str)));
+            }
+            // The marker written before the trailers existed
+            let marker = match (print_status, exit.code) {
+                (false, _) => Vec::new(),
+                (true, Some(code)) => framer
+                    .frame(Stream::Stderr, format!("Exit status exit status: {code}").as_bytes()),
+                (true, None) => {
+                    let signal = exit.signal.unwrap_or_default();
+                    framer.frame(Stream::Stderr, format!("Exit status signal: {signal}").as_bytes())
+                }
+            };
+            // Held back output goes before the exit frame
+            let mut last: Vec<_> = [marker, framer.flush(), framer.footer(&status)]
+                .into_iter()
+                .filter(|d| !d.is_empty())
+                .map(|d| Ok(Frame::data(Bytes::from(d))))
+                .collect();
+            last.push(Ok(Frame::trailers(exit_trailers(&exit, *killed))));
+            return last;
+        }
+    };
+    data.into_iter().filter(|d| !d.is_empty()).map(|d| Ok(Frame::data(Bytes::from(d)))).collect()
+}
+
+/// Response once the invocation ended, the stdout on success, with the content type
+/// reported by a `structured` result, a JSON error with the exit status
+/// and both outputs with the `failure` status otherwise
+async fn buffered_response(
+    mut events: mpsc::Receiver<std::io::Result<Event>>,
+    failure: StatusCode,
+    structured: bool,
+) -> HttpResponse {
+    let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+    let (mut killed, mut exit) = (None, None);
+    let mut content_type = structured.then(|| JSON_TYPE.to_string());
+    while let Some(event) = events.recv().await {
+        match event? {
+            Event::ContentType(reported) => content_type = Some(reported),
+            Event::Output(Stream::Stdout, data) => stdout.extend(data),
+            Event::Output(Stream::Stderr, data) => stderr.extend(data),
+            Event::Killed(kill) => killed = Some(kill),
+            Event::Exit(end) => exit = Some(end),
+        }
+    }
+    let exit = exit.ok_or(StatusCode::INTERNAL_SERVER_ERROR)?;
+    let headers = exit_trailers(&exit, killed);
+
+    let Some(error) = invocation::failure(killed, &exit) else {
+        return Ok(match content_type.and_then(|t| HeaderValue::from_str(&t).ok()) {
+            Some(content_type) => {
+                (StatusCode::OK, headers, [(header::CONTENT_TYPE, content_type)], stdout)
+                    .into_response()
+            }
+            None => (StatusCode::OK, headers, stdout).into_response(),
+        });
+    };
+    let status = failed_status(killed, failure);
+    // Escaping can grow the outputs manyfold, only their ends tell what went wrong
+    let tail = |output: &[u8]| {
+        String::from_utf8_lossy(&output[output.len().saturating_sub(MAX_ERROR_OUTPUT)..])
+            .into_owned()
+    };
+    let body = json!({
+        "error": error,
+        "exit": exit,
+        "stdout": tail(&stdout),
+        "stderr": tail(&stderr),
+    });
+    Ok((status, headers, Json(body)).into_response())
+}
+
+/// Status of a buffered response when the lambda failed, the `failure` status of the lambda
+/// unless the server killed it for a limit
+fn failed_status(killed: Option<Kill>, failure: StatusCode) -> StatusCode {
+    match killed {
+        Some(Kill::Timeout(_)) => StatusCode::GATEWAY_TIMEOUT,
+        Some(Kill::InputExceeded(_)) => StatusCode::PAYLOAD_TOO_LARGE,
+        Some(Kill::OutputExceeded(_)) => StatusCode::BAD_GATEWAY,
+        Some(Kill::OutOfMemory) | None => failure,
+    }
+}
+
+/// Exit status trailers, the signal one only when killed and the reason
+/// only when the server killed it
+fn exit_trailers(exit: &Exit, killed: Option<Kill>) -> HeaderMap {
+    let values = [
+        exit.code.map(|c| c.to_string()),
+        exit.signal.map(|s| s.to_string()),
+        killed.map(|k| k.as_str().to_string()),
+        Some(exit.duration_ms.to_string()),
+        Some(exit.user_ms.to_string()),
+        Some(exit.system_ms.to_string()),
+        Some(exit.max_rss_kb.to_string()),
+    ];
+    EXIT_TRAILERS
+        .iter()
+        .zip(values)
+        .filter_map(|(name, value)| {
+            Some((HeaderName::from_static(name), HeaderValue::from_str(&value?).ok()?))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn if_match_compares_strongly_and_if_none_match_weakly() {
+        let current = Some("\"abc\"");
+        assert!(etag_matches("*", current, true));
+        assert!(!etag_matches("*", None, true));
+        assert!(etag_matches("\"x\", \"abc\"", current, true));
+        assert!(!etag_matches("W/\"abc\"", current, true));
+        assert!(etag_matches("W/\"abc\"", current, false));
+        assert!(!etag_matches("\"abcd\", W/\"x\"", current, false));
+    }
+
+    #[test]
+    fn preconditions_refuse_stale_changes() {
+        let headers = |name, value| HeaderMap::from_iter([(name, HeaderValue::from_static(value))]);
+        let check = |map: &HeaderMap, current| check_preconditions(map, current).is_ok();
+        assert!(check(&HeaderMap::new(), Some("\"abc\"")));
+        assert!(check(&headers(header::IF_MATCH, "\"abc\""), Some("\"abc\"")));
+        assert!(!check(&headers(header::IF_MATCH, "W/\"abc\""), Some("\"abc\"")));
+        assert!(!check(&headers(header::IF_MATCH, "*"), None));
+        assert!(check(&headers(header::IF_NONE_MATCH, "*"), None));
+        assert!(!check(&headers(header::IF_NONE_MATCH, "*"), Some("\"abc\"")));
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7396() {
+        // The examples of RFC 7396 appendix A
+        let cases = [
+            (json!({"a": "b"}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "b"}), json!({"b": "c"}), json!({"a": "b", "b": "c"})),
+            (json!({"a": "b"}), json!({"a": null}), json!({})),
+            (json!({"a": "b", "b": "c"}), json!({"a": null}), json!({"b": "c"})),
+            (json!({"a": ["b"]}), json!({"a": "c"}), json!({"a": "c"})),
+            (json!({"a": "c"}), json!({"a": ["b"]}), json!({"a": ["b"]})),
+            (
+                json!({"a": {"b": "c"}}),
+                json!({"a": {"b": "d", "c": null}}),
+                json!({"a": {"b": "d"}}),
+            ),
+            (json!({"a": [{"b": "c"}]}), json!({"a": [1]}), json!({"a": [1]})),
+            (json!(["a", "b"]), json!(["c", "d"]), json!(["c", "d"])),
+            (json!({"a": "b"}), json!(["c"]), json!(["c"])),
+            (json!({"a": "foo"}), json!(null), json!(null)),
+            (json!({"a": "foo"}), json!("bar"), json!("bar")),
+            (json!({"e": null}), json!({"a": 1}), json!({"e": null, "a": 1})),
+            (json!([1, 2]), json!({"a": "b", "c": null}), json!({"a": "b"})),
+            (json!({}), json!({"a": {"bb": {"ccc": null}}}), json!({"a": {"bb": {}}})),
+        ];
+        for (mut target, patch, expected) in cases {
+            merge_patch(&mut target, &patch);
+            assert_eq!(target, expected, "{patch}");
+        }
+    }
+
+    #[tokio::test]
+    async fn chatty_lambdas_stop_the_wait_for_their_content_type() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let log = || Ok(Event::Output(Stream::Stderr, vec![b'.'; MAX_EARLY_OUTPUT / 2]));
+        for event in [log(), Ok(Event::ContentType("text/csv".to_string())), log(), log()] {
+            tx.send(event).await.unwrap();
+        }
+        let (reported, early) = reported_content_type(&mut rx).await;
+        assert_eq!(reported.as_deref(), Some("text/csv"));
+        assert_eq!(early.len(), 1);
+
+        tx.send(log()).await.unwrap();
+        tx.send(Ok(Event::ContentType("text/csv".to_string()))).await.unwrap();
+        let (fallback, held_back) = reported_content_type(&mut rx).await;
+        assert_eq!(fallback, None);
+        assert_eq!(held_back.len(), 3);
+        assert!(matches!(rx.recv().await, Some(Ok(Event::ContentType(_)))));
+    }
+
+    /// Data and trailers of the body frames of `events`
+    fn body(mut framer: Framer, events: Vec<Event>, print_status: bool) -> (String, HeaderMap) {
+        let mut killed = None;
+        let (mut data, mut trailers) = (String::new(), HeaderMap::new());
+        for event in events {
+            for frame in body_frames(&mut framer, &mut killed, Ok(event), print_status) {
+                match frame.unwrap().into_data() {
+                    Ok(bytes) => data.push_str(&String::from_utf8_lossy(&bytes)),
+                    Err(frame) => trailers = frame.into_trailers().unwrap(),
+                }
+            }
+        }
+        (data, trailers)
+    }
+
+    #[test]
+    fn kills_are_told_in_trailers_and_exit_frames_not_merged_output() {
+        let exit = Exit {
+            code: None,
+            signal: Some(9),
+            duration_ms: 1000,
+            user_ms: 1,
+            system_ms: 2,
+            max_rss_kb: 3,
+        };
+        let events = || {
+            vec![
+                Event::Output(Stream::Stdout, b"a,b\n".to_vec()),
+                Event::Killed(Kill::Timeout(1)),
+                Event::Exit(exit),
+            ]
+        };
+        let (merged, trailers) = body(Framer::new(Framing::Merged, None), events(), false);
+        assert_eq!(merged, "a,b\n");
+        assert_eq!(trailers["x-killed"], "timeout");
+        assert_eq!(trailers["x-exit-signal"], "9");
+        assert_eq!(trailers["x-duration-ms"], "1000");
+        assert!(!trailers.contains_key("x-exit-code"));
+
+        let (ndjson, _) = body(Framer::new(Framing::Ndjson, None), events(), false);
+        let exit_frame: Value = serde_json::from_str(ndjson.lines().last().unwrap()).unwrap();
+        assert_eq!(exit_frame["stream"], "exit");
+        assert_eq!(exit_frame["killed"], "timeout");
+        assert!(ndjson.contains("Timed out after 1s"), "{ndjson}");
+
+        let (marked, _) = body(Framer::new(Framing::Merged, None), events(), true);
+        assert_eq!(marked, "a,b\n\nTimed out after 1sExit status signal: 9");
+
+        let ended = vec![Event::Exit(Exit { code: Some(0), signal: None, ..exit })];
+        let (_, exited) = body(Framer::new(Framing::Merged, None), ended, false);
+        assert_eq!(exited["x-exit-code"], "0");
+        assert!(!exited.contains_key("x-killed"));
+    }
+
+    #[test]
+    fn failed_status_tells_the_limit_reached() {
+        let teapot = StatusCode::IM_A_TEAPOT;
+        assert_eq!(failed_status(None, teapot), teapot);
+        assert_eq!(failed_status(Some(Kill::OutOfMemory), teapot), teapot);
+        assert_eq!(failed_status(Some(Kill::Timeout(1)), teapot), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(
+            failed_status(Some(Kill::InputExceeded(1)), teapot),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        assert_eq!(failed_status(Some(Kill::OutputExceeded(1)), teapot), StatusCode::BAD_GATEWAY);
+    }
+
+    /// Buffered response to `events`
+    async fn buffered(events: Vec<Event>, structured: bool) -> Response {
+        let (tx, rx) = mpsc::channel(events.len());
+        for event in events {
+            tx.send(Ok(event)).await.unwrap();
+        }
+        std::mem::drop(tx);
+        buffered_response(rx, StatusCode::IM_A_TEAPOT, structured).await.into_response()
+    }
+
+    #[tokio::test]
+    async fn buffered_responses_map_the_exit_to_a_status() {
+        let exit = |code| Exit {
+            code: Some(code),
+            signal: None,
+            duration_ms: 1,
+            user_ms: 0,
+            system_ms: 0,
+            max_rss_kb: 0,
+        };
+        let ok = buffered(
+            vec![
+                Event::ContentType("text/csv".to_string()),
+                Event::Output(Stream::Stdout, b"a,b\n".to_vec()),
+                Event::Output(Stream::Stderr, b"log\n".to_vec()),
+                Event::Exit(exit(0)),
+            ],
+            true,
+        )
+        .await;
+        assert_eq!(ok.status(), StatusCode::OK);
+        assert_eq!(ok.headers()[header::CONTENT_TYPE], "text/csv");
+        assert_eq!(ok.headers()["x-exit-code"], "0");
+        let ok_body = axum::body::to_bytes(ok.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&ok_body[..], b"a,b\n");
+
+        let failed = buffered(
+            vec![Event::Output(Stream::Stderr, b"oops".to_vec()), Event::Exit(exit(2))],
+            false,
+        )
+        .await;
+        assert_eq!(failed.status(), StatusCode::IM_A_TEAPOT);
+        let failed_body = axum::body::to_bytes(failed.into_body(), usize::MAX).await.unwrap();
+        let error: Value = serde_json::from_slice(&failed_body).unwrap();
+        assert_eq!(error["error"], "Exited with code 2");
+        assert_eq!(error["stderr"], "oops");
+
+        let noisy = vec![
+            Event::Output(Stream::Stdout, vec![b'x'; MAX_ERROR_OUTPUT + 10]),
+            Event::Killed(Kill::OutputExceeded(10)),
+            Event::Exit(Exit { code: None, signal: Some(9), ..exit(0) }),
+        ];
+        let truncated = buffered(noisy, false).await;
+        assert_eq!(truncated.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(truncated.headers()["x-killed"], "output-exceeded");
+        let truncated_body = axum::body::to_bytes(truncated.into_body(), usize::MAX).await.unwrap();
+        let truncated_error: Value = serde_json::from_slice(&truncated_body).unwrap();
+        assert_eq!(truncated_error["stdout"].as_str().unwrap().len(), MAX_ERROR_OUTPUT);
+    }
+
+    #[tokio::test]
+    async fn read_body_refuses_bodies_past_the_cap() {
+        let read = read_body(Body::from(vec![b'a'; 10]), 10).await.unwrap();
+        assert_eq!(read.len(), 10);
+        let refused = read_body(Body::from(vec![b'a'; 11]), 10).await.unwrap_err();
+        assert!(matches!(refused, HttpErr::Status(StatusCode::PAYLOAD_TOO_LARGE)), "{refused:?}");
+    }
+
+    #[test]
+    fn patches_must_leave_a_single_kind() {
+        let lambda: Lambda =
+            serde_json::from_value(json!({ "bash": { "script": "true" } })).unwrap();
+        let status = |patch| patched(&lambda, &patch).map_err(|e| e.into_response().status());
+        let renamed = patched(&lambda, &json!({ "bash": { "script": "false" } })).unwrap();
+        assert_ne!(renamed.etag(), lambda.etag());
+        let both = json!({ "py": { "pycode": "pass" } });
+        assert_eq!(status(both).err(), Some(StatusCode::UNPROCESSABLE_ENTITY));
+        let none = json!({ "bash": null });
+        assert_eq!(status(none).err(), Some(StatusCode::UNPROCESSABLE_ENTITY));
+        let swapped = json!({ "bash": null, "py": { "pycode": "pass" } });
+        assert!(status(swapped).is_ok());
+    }
+
+    #[tokio::test]
+    async fn patches_changing_nothing_publish_no_version() {
+        let mut history = History::default();
+        let lambda = json!({ "bash": { "script": "true" }, "timeout": 5 });
+        let _ = history.publish(Arc::new(serde_json::from_value(lambda).unwrap()));
+        let state = Arc::new(RwLock::new(AppState {
+            lambdas: HashMap::from([("hello".to_string(), history)]),
+            sandboxs: HashMap::new(),
+            jobs: HashMap::new(),
+            job_slots: Arc::new(Semaphore::new(1)),
+            store: Arc::new(StoreKind::from(crate::store::Memory)),
+            registry_writer: Arc::default(),
+            sandbox_root: std::env::temp_dir(),
+        }));
+        let patch = |body: &'static str| {
+            let content_type = HeaderValue::from_static("application/merge-patch+json");
+            let headers = HeaderMap::from_iter([(header::CONTENT_TYPE, content_type)]);
+            lambda_patch(
+                Path("hello".to_string()),
+                State(Arc::clone(&state)),
+                headers,
+                Bytes::from(body),
+            )
+        };
+        let versions = || state.read().unwrap().lambdas["hello"].versions().len();
+
+        let unchanged = patch(r#"{"timeout": 5}"#).await.into_response();
+        assert_eq!(unchanged.status(), StatusCode::OK);
+        assert_eq!(versions(), 1);
+        let changed = patch(r#"{"timeout": 6}"#).await.into_response();
+        assert_eq!(changed.status(), StatusCode::OK);
+        assert_eq!(versions(), 2);
+        let ambiguous = patch(r#"{"py": {"pycode": "pass"}}"#).await.into_response();
+        assert_eq!(ambiguous.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(versions(), 2);
+    }
+}
@@ -0,0 +1,171 @@
+use anyhow::Result;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::sleep;
+
+/// Period of `cpu.max` in microseconds
+const CPU_PERIOD: u64 = 100_000;
+
+/// Mount point of the cgroup v2 hierarchy
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// Delay between the checks of a killed leaf until it is empty
+const REMOVE_BACKOFF: Duration = Duration::from_millis(10);
+
+/// Checks of a killed leaf before giving up on removing it
+const REMOVE_ATTEMPTS: u32 = 500;
+
+/// Counter naming invocation leaves
+static LEAF_ID: AtomicU64 = AtomicU64::new(0);
+
+/// cgroup v2 limits applied to each invocation
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Limits {
+    /// Delegated cgroup v2 directory the invocation leaves are created in
+    pub parent: String,
+    /// `memory.max` in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub memory_max: Option<u64>,
+    /// `cpu.max` as a number of cpus, e.g. 0.5
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu_max: Option<f64>,
+    /// `pids.max`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pids_max: Option<u64>,
+}
+
+impl Limits {
+    /// Create a leaf cgroup with the limits, `cmd` joins it before exec
+    /// # Errors
+    ///     IO errors when the parent cgroup isn't delegated to us
+    pub fn enter(&self, cmd: &mut Command) -> Result<Leaf> {
+        let leaf = self.create()?;
+        join_on_exec(cmd, &leaf.procs);
+        Ok(leaf)
+    }
+
+    /// Create a leaf cgroup with the limits, for a process placed in it by someone else
+    /// # Errors
+    ///     IO errors when the parent cgroup isn't delegated to us
+    pub fn create(&self) -> Result<Leaf> {
+        let id = LEAF_ID.fetch_add(1, Ordering::Relaxed);
+        let path = format!("{}/invocation-{}-{id}", self.parent, std::process::id());
+        fs::create_dir_all(&path)?;
+        // From here the leaf is removed on error
+        let leaf = Leaf {
+            procs: fs::File::options().write(true).open(path.clone() + "/cgroup.procs")?,
+            path,
+        };
+
+        if let Some(bytes) = self.memory_max {
+            fs::write(leaf.path.clone() + "/memory.max", bytes.to_string())?;
+        }
+        if let Some(cpus) = self.cpu_max {
+            #[allow(
+                clippy::cast_possible_truncation,
+                clippy::cast_sign_loss,
+                clippy::cast_precision_loss,
+                reason = "quota is a small positive number of microseconds"
+            )]
+            let quota = (cpus * CPU_PERIOD as f64).max(1.0) as u64;
+            fs::write(leaf.path.clone() + "/cpu.max", format!("{quota} {CPU_PERIOD}"))?;
+        }
+        if let Some(pids) = self.pids_max {
+            fs::write(leaf.path.clone() + "/pids.max", pids.to_string())?;
+        }
+        Ok(leaf)
+    }
+}
+
+/// Make the spawned process join the cgroup of `procs` before exec
+fn join_on_exec(cmd: &mut Command, procs: &fs::File) {
+    let fd = procs.as_raw_fd();
+    // SAFETY: the closure only calls write(2) which is async-signal-safe,
+    // the fd stays open in the parent as long as the `Leaf` lives, which outlives spawn
+    #[allow(unsafe_code, reason = "joining the cgroup must happen between fork and exec")]
+    let _ = unsafe {
+        cmd.pre_exec(move || {
+            // Writing 0 moves the calling process
+            match libc::write(fd, b"0".as_ptr().cast(), 1) {
+                1 => Ok(()),
+                _ => Err(std::io::Error::last_os_error()),
+            }
+        })
+    };
+}
+
+/// The cgroup of a single invocation, removed on drop
+#[derive(Debug)]
+pub struct Leaf {
+    path: String,
+    procs: fs::File,
+}
+
+impl Leaf {
+    /// Path of the leaf from the root of the cgroup hierarchy, as OCI runtimes want it
+    #[must_use]
+    pub fn cgroups_path(&self) -> Option<&str> {
+        self.path.strip_prefix(CGROUP_ROOT).filter(|p| p.starts_with('/'))
+    }
+
+    /// Whether the OOM killer killed a process of the invocation
+    #[must_use]
+    pub fn oom_killed(&self) -> bool {
+        fs::read_to_string(self.path.clone() + "/memory.events").is_ok_and(|events| {
+            events
+                .lines()
+                .filter_map(|l| l.strip_prefix("oom_kill "))
+                .any(|n| n.trim().parse::<u64>().is_ok_and(|n| n > 0))
+        })
+    }
+}
+
+impl Drop for Leaf {
+    fn drop(&mut self) {
+        // Kill leftover descendants so the leaf can be removed
+        if let Err(e) = fs::write(self.path.clone() + "/cgroup.kill", "1") {
+            warn!("Failed to kill cgroup {}: {e}", self.path);
+        }
+        // The kill is asynchronous, the leaf is busy until its processes are gone
+        let path = std::mem::take(&mut self.path);
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => std::mem::drop(runtime.spawn(remove(path))),
+            Err(_) => {
+                if let Err(e) = fs::remove_dir(&path) {
+                    warn!("Failed to remove cgroup {path}: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Whether processes are still in the cgroup `path`
+fn populated(path: &str) -> bool {
+    fs::read_to_string(path.to_string() + "/cgroup.events")
+        .is_ok_and(|events| events.lines().any(|l| l == "populated 1"))
+}
+
+/// Remove a killed leaf once it is empty
+async fn remove(path: String) {
+    for _ in 0..REMOVE_ATTEMPTS {
+        if !populated(&path) {
+            match fs::remove_dir(&path) {
+                // Also removed by an OCI runtime deleting its container
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+                Err(e) if e.raw_os_error() != Some(libc::EBUSY) => {
+                    warn!("Failed to remove cgroup {path}: {e}");
+                    return;
+                }
+                Err(_) => {}
+                Ok(()) => return,
+            }
+        }
+        sleep(REMOVE_BACKOFF).await;
+    }
+    warn!("Failed to remove cgroup {path}: still populated");
+}
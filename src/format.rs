@@ -0,0 +1,406 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine as _};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::hash::{BuildHasher, Hasher, RandomState};
+
+/// Media ranges of an `Accept` header with their parameters and a quality above zero
+fn media_ranges(accept: &str) -> impl Iterator<Item = (String, Vec<(&str, &str)>, f32)> {
+    accept.split(',').filter_map(|range| {
+        let mut parts = range.split(';').map(str::trim);
+        let media_type = parts.next()?.to_ascii_lowercase();
+        let params = parts.filter_map(|p| p.split_once('=')).collect::<Vec<_>>();
+        let quality = params
+            .iter()
+            .find(|(k, _)| *k == "q")
+            .map_or(Some(1.0), |(_, q)| q.parse::<f32>().ok())?;
+        (quality > 0.0).then_some((media_type, params, quality))
+    })
+}
+
+/// Output format of a structured lambda result, negotiated from the `Accept` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// JSON list of records
+    JsonRecords,
+    /// JSON object with `columns`, `index` and `data`
+    JsonSplit,
+    /// Comma separated values
+    Csv,
+    /// Apache Parquet file
+    Parquet,
+    /// Apache Arrow IPC stream
+    Arrow,
+}
+
+impl OutputFormat {
+    /// Name given to the python runner
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::JsonRecords => "records",
+            Self::JsonSplit => "split",
+            Self::Csv => "csv",
+            Self::Parquet => "parquet",
+            Self::Arrow => "arrow",
+        }
+    }
+
+    /// Format for a single media type, the `orient` parameter selects the JSON layout
+    fn from_media_type(media_type: &str, params: &[(&str, &str)]) -> Option<Self> {
+        match media_type {
+            "*/*" | "application/*" | "application/json" => {
+                match params.iter().find(|(k, _)| *k == "orient").map(|(_, v)| *v) {
+                    Some("split") => Some(Self::JsonSplit),
+                    None | Some("records") => Some(Self::JsonRecords),
+                    Some(_) => None,
+                }
+            }
+            "text/*" | "text/csv" => Some(Self::Csv),
+            "application/vnd.apache.parquet" | "application/x-parquet" => Some(Self::Parquet),
+            "application/vnd.apache.arrow.stream" => Some(Self::Arrow),
+            _ => None,
+        }
+    }
+
+    /// Pick the preferred supported format from an `Accept` header
+    /// `None` when nothing acceptable is supported
+    #[must_use]
+    pub fn negotiate(accept: Option<&str>) -> Option<Self> {
+        let Some(accept) = accept.filter(|a| !a.trim().is_empty()) else {
+            return Some(Self::JsonRecords);
+        };
+
+        let mut candidates = media_ranges(accept)
+            .filter_map(|(media_type, params, quality)| {
+                Some((quality, Self::from_media_type(&media_type, &params)?))
+            })
+            .collect::<Vec<_>>();
+        // Stable sort keeps the header order for equal qualities
+        candidates.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        candidates.first().map(|(_, format)| *format)
+    }
+}
+
+/// Output stream of a lambda
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stream {
+    /// Standard output
+    Stdout,
+    /// Standard error
+    Stderr,
+}
+
+impl Stream {
+    /// Name of the stream in frames
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Stdout => "stdout",
+            Self::Stderr => "stderr",
+        }
+    }
+}
+
+/// Layout of the lambda streams in the response body, negotiated from the `Accept` header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Framing {
+    /// Raw bytes, both streams interleaved as they come
+    Merged,
+    /// `application/x-ndjson` lines `{"stream":"stdout","data":"..."}`
+    Ndjson,
+    /// `multipart/mixed` with a part per chunk, its stream in the `X-Stream` header
+    Multipart {
+        /// Parts delimiter
+        boundary: String,
+    },
+}
+
+impl Framing {
+    /// Pick the preferred framing from an `Accept` header, `Merged` unless one is asked
+    /// with a quality no other media range exceeds
+    #[must_use]
+    pub fn negotiate(accept: Option<&str>) -> Self {
+        let mut candidates = media_ranges(accept.unwrap_or_default())
+            .map(|(media_type, _, quality)| {
+                let framing = match media_type.as_str() {
+                    "application/x-ndjson" => Some(Self::Ndjson),
+                    "multipart/mixed" => {
+                        let boundary =
+                            format!("freeitw-{:016x}", RandomState::new().build_hasher().finish());
+                        Some(Self::Multipart { boundary })
+                    }
+                    _ => None,
+                };
+                (quality, framing)
+            })
+            .collect::<Vec<_>>();
+        // Stable sort keeps the header order for equal qualities, a framing goes first
+        // so `multipart/mixed, text/csv` frames a CSV result
+        candidates.sort_by(|(a, x), (b, y)| b.total_cmp(a).then(y.is_some().cmp(&x.is_some())));
+        candidates.into_iter().next().and_then(|(_, framing)| framing).unwrap_or(Self::Merged)
+    }
+
+    /// Whether the streams are told apart
+    #[must_use]
+    pub fn is_framed(&self) -> bool {
+        *self != Self::Merged
+    }
+
+    /// Response `Content-Type`, the one of the stdout when not framed
+    #[must_use]
+    pub fn content_type(&self, stdout_type: Option<&str>) -> Option<String> {
+        match self {
+            Self::Merged => stdout_type.map(str::to_string),
+            Self::Ndjson => Some("application/x-ndjson".to_string()),
+            Self::Multipart { boundary } => Some(format!("multipart/mixed; boundary={boundary}")),
+        }
+    }
+}
+
+/// Encoder of the lambda streams into the response body
+#[derive(Debug)]
+pub struct Framer {
+    framing: Framing,
+    /// `Content-Type` of the stdout parts
+    stdout_type: String,
+    /// Incomplete UTF-8 sequences held back until the next chunk, by stream
+    pending: [Vec<u8>; 2],
+}
+
+impl Framer {
+    /// Encoder for the `framing`, `stdout_type` is the content type of structured results
+    #[must_use]
+    pub fn new(framing: Framing, stdout_type: Option<&str>) -> Self {
+        let stdout_type = stdout_type.unwrap_or("application/octet-stream").to_string();
+        Self { framing, stdout_type, pending: [Vec::new(), Vec::new()] }
+    }
+
+    /// Whether the streams are told apart
+    #[must_use]
+    pub fn is_framed(&self) -> bool {
+        self.framing.is_framed()
+    }
+
+    /// Encode a chunk of `stream`
+    pub fn frame(&mut self, stream: Stream, data: &[u8]) -> Vec<u8> {
+        if data.is_empty() {
+            return Vec::new();
+        }
+        match &self.framing {
+            Framing::Merged => data.to_vec(),
+            Framing::Ndjson => {
+                // JSON strings hold text, a chunk may end in the middle of a character,
+                // what still isn't UTF-8 is sent base64 encoded
+                let pending = &mut self.pending[stream as usize];
+                pending.extend_from_slice(data);
+                let complete = match std::str::from_utf8(pending) {
+                    Err(e) if e.error_len().is_none() => e.valid_up_to(),
+                    _ => pending.len(),
+                };
+                let text = pending.drain(..complete).collect::<Vec<_>>();
+                ndjson_line(stream, &text)
+            }
+            Framing::Multipart { boundary } => {
+                let content_type = match stream {
+                    Stream::Stdout => &self.stdout_type,
+                    Stream::Stderr => "text/plain; charset=utf-8",
+                };
+                let mut part = format!(
+                    "--{boundary}\r\nContent-Type: {content_type}\r\nX-Stream: {}\r\n\r\n",
+                    stream.as_str()
+                )
+                .into_bytes();
+                part.extend_from_slice(data);
+                part.extend_from_slice(b"\r\n");
+                part
+            }
+        }
+    }
+
+    /// Final frame holding the exit `status` object and closing the body,
+    /// nothing when not framed. What is held back must be flushed before
+    pub fn footer(&self, status: &Value) -> Vec<u8> {
+        match &self.framing {
+            Framing::Merged => Vec::new(),
+            Framing::Ndjson => {
+                let mut frame = Map::new();
+                let _ = frame.insert("stream".to_string(), "exit".into());
+                if let Value::Object(fields) = status {
+                    frame.extend(fields.clone());
+                }
+                let mut line = Value::Object(frame).to_string().into_bytes();
+                line.push(b'\n');
+                line
+            }
+            Framing::Multipart { boundary } => format!(
+                "--{boundary}\r\nContent-Type: application/json\r\nX-Stream: exit\r\n\r\n{status}\r\n--{boundary}--\r\n"
+            )
+            .into_bytes(),
+        }
+    }
+
+    /// Flush what is held back, the incomplete characters of NDJSON frames
+    pub fn flush(&mut self) -> Vec<u8> {
+        match &self.framing {
+            Framing::Ndjson => {
+                let stdout = std::mem::take(&mut self.pending[Stream::Stdout as usize]);
+                let stderr = std::mem::take(&mut self.pending[Stream::Stderr as usize]);
+                let mut end = ndjson_line(Stream::Stdout, &stdout);
+                end.extend(ndjson_line(Stream::Stderr, &stderr));
+                end
+            }
+            Framing::Merged | Framing::Multipart { .. } => Vec::new(),
+        }
+    }
+}
+
+/// A NDJSON frame of `data`, nothing when empty.
+/// Bytes which aren't UTF-8 are base64 encoded, with `"encoding": "base64"`
+fn ndjson_line(stream: Stream, data: &[u8]) -> Vec<u8> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+    let frame = match std::str::from_utf8(data) {
+        Ok(text) => json!({ "stream": stream.as_str(), "data": text }),
+        Err(_) => {
+            json!({ "stream": stream.as_str(), "data": BASE64.encode(data), "encoding": "base64" })
+        }
+    };
+    let mut line = frame.to_string().into_bytes();
+    line.push(b'\n');
+    line
+}
+
+/// Request body format parsed into a `DataFrame`, selected from the `Content-Type` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    /// Comma separated values, `header=absent` when the first row is data
+    Csv {
+        /// First row holds the column names
+        header: bool,
+    },
+    /// Tab separated values
+    Tsv,
+    /// JSON records
+    Json,
+    /// Newline delimited JSON records
+    Ndjson,
+    /// Apache Parquet file
+    Parquet,
+}
+
+impl InputFormat {
+    /// Name given to the python runner
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Csv { header: true } => "csv",
+            Self::Csv { header: false } => "csv-noheader",
+            Self::Tsv => "tsv",
+            Self::Json => "json",
+            Self::Ndjson => "ndjson",
+            Self::Parquet => "parquet",
+        }
+    }
+
+    /// Format of a `Content-Type` header, `None` when unsupported
+    #[must_use]
+    pub fn from_content_type(content_type: &str) -> Option<Self> {
+        let mut parts = content_type.split(';').map(str::trim);
+        let media_type = parts.next()?.to_ascii_lowercase();
+        let mut params = parts.filter_map(|p| p.split_once('='));
+        match media_type.as_str() {
+            "text/csv" => {
+                let header = !params.any(|(k, v)| {
+                    k.eq_ignore_ascii_case("header") && v.eq_ignore_ascii_case("absent")
+                });
+                Some(Self::Csv { header })
+            }
+            "text/tab-separated-values" => Some(Self::Tsv),
+            "application/json" => Some(Self::Json),
+            "application/x-ndjson" | "application/jsonl" => Some(Self::Ndjson),
+            "application/vnd.apache.parquet" | "application/x-parquet" => Some(Self::Parquet),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_format_follows_the_content_type() {
+        let parse = InputFormat::from_content_type;
+        assert_eq!(parse("text/csv"), Some(InputFormat::Csv { header: true }));
+        assert_eq!(
+            parse("Text/CSV; charset=utf-8; header=absent"),
+            Some(InputFormat::Csv { header: false })
+        );
+        assert_eq!(parse("text/csv;header=present"), Some(InputFormat::Csv { header: true }));
+        assert_eq!(parse("text/tab-separated-values"), Some(InputFormat::Tsv));
+        assert_eq!(parse("application/json; charset=utf-8"), Some(InputFormat::Json));
+        assert_eq!(parse("application/x-ndjson"), Some(InputFormat::Ndjson));
+        assert_eq!(parse("application/jsonl"), Some(InputFormat::Ndjson));
+        assert_eq!(parse("application/x-parquet"), Some(InputFormat::Parquet));
+        assert_eq!(parse("application/octet-stream"), None);
+        assert_eq!(parse(""), None);
+        assert_eq!(InputFormat::Csv { header: false }.as_str(), "csv-noheader");
+    }
+
+    #[test]
+    fn output_format_follows_the_quality() {
+        let negotiate = OutputFormat::negotiate;
+        assert_eq!(negotiate(None), Some(OutputFormat::JsonRecords));
+        assert_eq!(
+            negotiate(Some("text/csv;q=0.5, application/vnd.apache.parquet")),
+            Some(OutputFormat::Parquet)
+        );
+        assert_eq!(
+            negotiate(Some("application/json;orient=split, text/csv")),
+            Some(OutputFormat::JsonSplit)
+        );
+        assert_eq!(negotiate(Some("text/csv;q=0, */*;q=0.1")), Some(OutputFormat::JsonRecords));
+        assert_eq!(negotiate(Some("image/png")), None);
+    }
+
+    #[test]
+    fn framing_is_picked_only_when_preferred() {
+        let negotiate = Framing::negotiate;
+        assert_eq!(negotiate(None), Framing::Merged);
+        assert_eq!(
+            negotiate(Some("application/json, application/x-ndjson;q=0.1")),
+            Framing::Merged
+        );
+        assert_eq!(
+            negotiate(Some("application/json;q=0.5, application/x-ndjson")),
+            Framing::Ndjson
+        );
+        assert_eq!(negotiate(Some("text/csv, application/x-ndjson;q=0")), Framing::Merged);
+        assert!(matches!(negotiate(Some("text/csv, multipart/mixed")), Framing::Multipart { .. }));
+    }
+
+    #[test]
+    fn ndjson_keeps_split_characters_and_encodes_binary() {
+        let mut framer = Framer::new(Framing::Ndjson, None);
+        let e_acute = "é".as_bytes();
+        assert_eq!(
+            framer.frame(Stream::Stdout, &[b'a', e_acute[0]]),
+            b"{\"stream\":\"stdout\",\"data\":\"a\"}\n"
+        );
+        assert_eq!(
+            framer.frame(Stream::Stdout, &e_acute[1..]),
+            "{\"stream\":\"stdout\",\"data\":\"é\"}\n".as_bytes()
+        );
+        assert_eq!(
+            framer.frame(Stream::Stdout, b"PAR1\xff\x00"),
+            b"{\"stream\":\"stdout\",\"data\":\"UEFSMf8A\",\"encoding\":\"base64\"}\n"
+        );
+        let _ = framer.frame(Stream::Stderr, &e_acute[..1]);
+        assert_eq!(
+            framer.flush(),
+            b"{\"stream\":\"stderr\",\"data\":\"ww==\",\"encoding\":\"base64\"}\n"
+        );
+    }
+}
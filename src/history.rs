@@ -0,0 +1,257 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use similar::TextDiff;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::lambda_app::{Lambda, Trait as LambdaTrait};
+
+/// An immutable published version of a lambda
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Version {
+    /// Version number, from 1
+    pub number: u32,
+    /// Publication time in seconds since the epoch
+    pub published: u64,
+    /// The lambda as published
+    pub lambda: Arc<Lambda>,
+}
+
+/// Published versions of a lambda and its aliases
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(from = "Stored")]
+pub struct History {
+    /// Versions in publication order, version `n` at index `n - 1`
+    versions: Vec<Version>,
+    /// Aliases to version numbers
+    #[serde(default)]
+    aliases: BTreeMap<String, u32>,
+}
+
+/// A stored lambda, from before versioning or not
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Stored {
+    /// Versions and aliases
+    History {
+        /// Versions in publication order
+        versions: Vec<Version>,
+        /// Aliases to version numbers
+        #[serde(default)]
+        aliases: BTreeMap<String, u32>,
+    },
+    /// A lambda stored before versioning, becomes version 1
+    Unversioned(Lambda),
+}
+
+impl From<Stored> for History {
+    fn from(stored: Stored) -> Self {
+        match stored {
+            Stored::History { versions, aliases } => Self { versions, aliases },
+            Stored::Unversioned(lambda) => {
+                let mut history = Self::default();
+                let _ = history.publish(Arc::new(lambda));
+                history
+            }
+        }
+    }
+}
+
+/// Split a lambda reference `name`, `name@3` or `name@prod` into its name and version or alias
+#[must_use]
+pub fn parse_reference(reference: &str) -> (&str, Option<&str>) {
+    match reference.split_once('@') {
+        Some((name, version)) => (name, Some(version)),
+        None => (reference, None),
+    }
+}
+
+/// Whether `version` is written as a version number rather than an alias
+fn is_number(version: &str) -> bool {
+    !version.is_empty() && version.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Whether `alias` can name a version, it must not be mistaken for a version number
+#[must_use]
+pub fn valid_alias(alias: &str) -> bool {
+    !alias.is_empty() && !alias.contains('@') && !is_number(alias)
+}
+
+impl History {
+    /// Latest version
+    #[must_use]
+    pub fn latest(&self) -> Option<&Version> {
+        self.versions.last()
+    }
+
+    /// Version by number
+    #[must_use]
+    pub fn get(&self, version: u32) -> Option<&Version> {
+        let index = usize::try_from(version).ok()?.checked_sub(1)?;
+        self.versions.get(index)
+    }
+
+    /// Version by number or alias, the latest one without
+    #[must_use]
+    pub fn resolve(&self, version: Option<&str>) -> Option<&Version> {
+        match version {
+            None => self.latest(),
+            Some(version) if is_number(version) => self.get(version.parse().ok()?),
+            Some(alias) => self.get(*self.aliases.get(alias)?),
+        }
+    }
+
+    /// All versions in publication order
+    #[must_use]
+    pub fn versions(&self) -> &[Version] {
+        &self.versions
+    }
+
+    /// Aliases to version numbers
+    #[must_use]
+    pub const fn aliases(&self) -> &BTreeMap<String, u32> {
+        &self.aliases
+    }
+
+    /// Publish a new version of the lambda, returns its number
+    pub fn publish(&mut self, lambda: Arc<Lambda>) -> u32 {
+        let number = self.latest().map_or(1, |v| v.number + 1);
+        let published =
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs());
+        self.versions.push(Version { number, published, lambda });
+        number
+    }
+
+    /// Point `alias` to an existing version, returns whether it exists
+    pub fn set_alias(&mut self, alias: String, version: u32) -> bool {
+        if self.get(version).is_none() {
+            return false;
+        }
+        let _ = self.aliases.insert(alias, version);
+        true
+    }
+
+    /// Remove an alias, returns whether it existed
+    pub fn remove_alias(&mut self, alias: &str) -> bool {
+        self.aliases.remove(alias).is_some()
+    }
+}
+
+impl Version {
+    /// Code of the lambda and its metadata, the other fields as pretty JSON
+    fn sources(&self) -> (&'static str, String, String) {
+        let (field, code) = self.lambda.app.code();
+        let mut metadata = serde_json::to_value(&*self.lambda).unwrap_or_default();
+        if let Some(fields) = metadata.as_object_mut() {
+            // The code is a field of the kind object
+            for kind in fields.values_mut().filter_map(Value::as_object_mut) {
+                let _ = kind.remove(field);
+            }
+        }
+        let metadata = serde_json::to_string_pretty(&metadata).unwrap_or_default() + "\n";
+        (field, code.to_string(), metadata)
+    }
+}
+
+/// Unified diff of the code and the metadata of two versions of the lambda `name`,
+/// empty when they are the same
+#[must_use]
+pub fn diff(name: &str, from: &Version, to: &Version) -> String {
+    let (from_field, from_code, from_metadata) = from.sources();
+    let (to_field, to_code, to_metadata) = to.sources();
+    let header = |version: &Version, file: &str| format!("{name}@{}/{file}", version.number);
+
+    let code = TextDiff::from_lines(&from_code, &to_code)
+        .unified_diff()
+        .header(&header(from, from_field), &header(to, to_field))
+        .to_string();
+    let metadata = TextDiff::from_lines(&from_metadata, &to_metadata)
+        .unified_diff()
+        .header(&header(from, "metadata.json"), &header(to, "metadata.json"))
+        .to_string();
+    code + &metadata
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn lambda(script: &str) -> Arc<Lambda> {
+        Arc::new(serde_json::from_value(json!({ "bash": { "script": script } })).unwrap())
+    }
+
+    #[test]
+    fn references_split_on_the_first_at() {
+        assert_eq!(parse_reference("hello"), ("hello", None));
+        assert_eq!(parse_reference("hello@3"), ("hello", Some("3")));
+        assert_eq!(parse_reference("hello@prod"), ("hello", Some("prod")));
+        assert_eq!(parse_reference("hello@"), ("hello", Some("")));
+    }
+
+    #[test]
+    fn aliases_cant_look_like_numbers() {
+        assert!(valid_alias("prod"));
+        assert!(valid_alias("v1"));
+        assert!(valid_alias("+1"));
+        assert!(!valid_alias(""));
+        assert!(!valid_alias("1"));
+        assert!(!valid_alias("007"));
+        assert!(!valid_alias("99999999999"));
+        assert!(!valid_alias("a@b"));
+    }
+
+    #[test]
+    fn versions_resolve_by_number_alias_or_latest() {
+        let mut history = History::default();
+        assert!(history.resolve(None).is_none());
+        assert_eq!(history.publish(lambda("echo 1")), 1);
+        assert_eq!(history.publish(lambda("echo 2")), 2);
+        assert!(history.set_alias("prod".to_string(), 1));
+        assert!(history.set_alias("+1".to_string(), 2));
+        assert!(!history.set_alias("next".to_string(), 3));
+
+        let number = |versions: &History, version| versions.resolve(version).map(|v| v.number);
+        assert_eq!(number(&history, None), Some(2));
+        assert_eq!(number(&history, Some("1")), Some(1));
+        assert_eq!(number(&history, Some("01")), Some(1));
+        assert_eq!(number(&history, Some("prod")), Some(1));
+        assert_eq!(number(&history, Some("+1")), Some(2));
+        assert_eq!(number(&history, Some("0")), None);
+        assert_eq!(number(&history, Some("3")), None);
+        assert_eq!(number(&history, Some("99999999999")), None);
+        assert_eq!(number(&history, Some("next")), None);
+        assert_eq!(number(&history, Some("")), None);
+
+        assert!(history.remove_alias("prod"));
+        assert!(!history.remove_alias("prod"));
+        assert_eq!(number(&history, Some("prod")), None);
+    }
+
+    #[test]
+    fn diffs_show_the_code_and_the_metadata_apart() {
+        let mut history = History::default();
+        let _ = history.publish(lambda("echo 1\necho 2\n"));
+        let _ = history.publish(lambda("echo 1\necho 3\n"));
+        let described =
+            json!({ "bash": { "script": "echo 1\necho 3\n" }, "description": "counts" });
+        let _ = history.publish(Arc::new(serde_json::from_value(described).unwrap()));
+        let version = |number| history.get(number).unwrap();
+
+        assert_eq!(diff("hello", version(1), version(1)), "");
+
+        let code = diff("hello", version(1), version(2));
+        assert!(code.starts_with("--- hello@1/script\n+++ hello@2/script\n"), "{code}");
+        assert!(code.contains("\n-echo 2\n+echo 3\n"), "{code}");
+        assert!(!code.contains("metadata.json"), "{code}");
+
+        let metadata = diff("hello", version(2), version(3));
+        assert!(
+            metadata.starts_with("--- hello@2/metadata.json\n+++ hello@3/metadata.json\n"),
+            "{metadata}"
+        );
+        assert!(metadata.contains("+  \"description\": \"counts\""), "{metadata}");
+        assert!(!metadata.contains("echo"), "{metadata}");
+    }
+}
@@ -0,0 +1,497 @@
+use log::{info, warn};
+use std::time::{Duration, Instant};
+use std::{fmt, io};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::Child,
+    select,
+    sync::mpsc,
+    time::sleep,
+};
+
+use crate::format::Stream;
+use crate::process::{kill_group, wait_exit, Exit};
+use crate::sandbox::Scope;
+
+/// What happens during an invocation, `Exit` comes last
+#[derive(Debug)]
+pub enum Event {
+    /// The content type a structured result reported, before its output
+    ContentType(String),
+    /// A chunk of the lambda output
+    Output(Stream, Vec<u8>),
+    /// The lambda was killed
+    Killed(Kill),
+    /// The lambda ended
+    Exit(Exit),
+}
+
+/// Why a lambda was killed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kill {
+    /// Ran past its timeout in seconds
+    Timeout(u64),
+    /// Was sent more input bytes than allowed
+    InputExceeded(u64),
+    /// Wrote more output bytes than allowed
+    OutputExceeded(u64),
+    /// Killed by the OOM killer of its cgroup
+    OutOfMemory,
+}
+
+impl Kill {
+    /// Name of the reason, in the `X-Killed` trailer and the exit frame
+    #[must_use]
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Timeout(_) => "timeout",
+            Self::InputExceeded(_) => "input-exceeded",
+            Self::OutputExceeded(_) => "output-exceeded",
+            Self::OutOfMemory => "out-of-memory",
+        }
+    }
+}
+
+impl fmt::Display for Kill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout(secs) => write!(f, "Timed out after {secs}s"),
+            Self::InputExceeded(max) => write!(f, "Killed: input exceeded {max} bytes"),
+            Self::OutputExceeded(max) => write!(f, "Truncated: output exceeded {max} bytes"),
+            Self::OutOfMemory => write!(f, "Killed: out of memory"),
+        }
+    }
+}
+
+/// Why an invocation failed, `None` when the lambda exited with code 0
+#[must_use]
+pub fn failure(killed: Option<Kill>, exit: &Exit) -> Option<String> {
+    match (killed, exit.code, exit.signal) {
+        (Some(kill), _, _) => Some(kill.to_string()),
+        (None, Some(0), _) => None,
+        (None, _, Some(signal)) => Some(format!("Killed by signal {signal}")),
+        (None, code, None) => Some(format!("Exited with code {}", code.unwrap_or_default())),
+    }
+}
+
+/// Limits and settings of an invocation
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options {
+    /// Seconds before the lambda is killed
+    pub timeout: Option<u64>,
+    /// Max input bytes forwarded to the lambda
+    pub max_input: Option<u64>,
+    /// Max stdout and stderr bytes read from the lambda
+    pub max_output: Option<u64>,
+    /// Stderr goes to the server logs instead of the events
+    pub log_stderr: bool,
+    /// Stdout starts with the line reporting the content type of a structured result
+    pub typed_output: bool,
+}
+
+/// Start of the line reporting the content type of a structured result
+const CONTENT_TYPE_LINE: &[u8] = b"FREEITW-Content-Type: ";
+/// Longest line looked at for a reported content type
+const MAX_CONTENT_TYPE_LINE: usize = 256;
+/// Longest logged stderr line, longer ones are logged in pieces
+const MAX_LOG_LINE: usize = 64 << 10;
+
+/// Stream `input` to the stdin of a spawned lambda and its output back as events,
+/// killing its process group on a limit or when the receiver is dropped.
+/// The child is only reaped through its `Child`, then the `Scope` is released.
+/// # Errors
+///     when the child standard IOs aren't piped
+pub fn run<R>(
+    name: String,
+    mut child: Child,
+    scope: Scope,
+    started: Instant,
+    input: R,
+    options: Options,
+) -> io::Result<mpsc::Receiver<io::Result<Event>>>
+where
+    R: AsyncRead + Send + 'static,
+{
+    let missing = || io::Error::other("child standard IO not piped");
+    let pid = child.id().ok_or_else(missing)?;
+    let mut stdin = child.stdin.take().ok_or_else(missing)?;
+    let stdout = BufReader::new(child.stdout.take().ok_or_else(missing)?);
+    let stderr = BufReader::new(child.stderr.take().ok_or_else(missing)?);
+
+    let mut stdout = Box::pin(stdout);
+    let mut stderr = Box::pin(stderr);
+    let mut stdout_buf = vec![0_u8; 128];
+    let mut stderr_buf = vec![0_u8; 128];
+
+    let (tx, rx) = mpsc::channel::<io::Result<Event>>(4);
+    let Options { timeout, max_input, max_output, log_stderr, typed_output } = options;
+
+    // Kill the lambda when it runs past its deadline
+    let deadline = async move {
+        match timeout {
+            Some(secs) => sleep(Duration::from_secs(secs)).await,
+            None => std::future::pending().await,
+        }
+    };
+    // Observed without reaping to get its resource usage, tokio reaps it at the end
+    let exited = wait_exit(pid, started);
+    // Forward the input on its own, a lambda not reading its stdin blocks the writes
+    // but not the other arms, the deadline included. The stdin is closed when it ends
+    let feed = async move {
+        let mut input = Box::pin(input);
+        let mut buf = vec![0_u8; 128];
+        let (mut input_len, mut writable) = (0, true);
+        loop {
+            let n = input.read(&mut buf).await?;
+            if n == 0 {
+                return Ok(None);
+            }
+            let (chunk, exceeded) = take_budget(&buf[..n], &mut input_len, max_input);
+            // The rest of the input is still read once the lambda closed its stdin
+            writable = writable && stdin.write_all(chunk).await.is_ok();
+            if exceeded {
+                return Ok(Some(Kill::InputExceeded(max_input.unwrap_or_default())));
+            }
+        }
+    };
+
+    #[allow(clippy::let_underscore_future, reason = "the task is detached on purpose")]
+    let _ = tokio::spawn(async move {
+        let (mut feeding, mut stdout_open, mut stderr_open) = (true, true, true);
+        let mut output_len = 0;
+        // Stdout held until its content type line is complete
+        let mut typed_head = typed_output.then(Vec::new);
+        // Logged stderr, up to its last incomplete line
+        let mut stderr_line = Vec::new();
+        let mut ended = false;
+        tokio::pin!(deadline);
+        tokio::pin!(exited);
+        tokio::pin!(feed);
+        // Sending fails when the receiver is dropped, e.g. the client went away
+        let streamed = async {
+            // Set once ended, output still in the pipes is read until they close
+            let mut exit = None;
+            let exit = loop {
+                if let Some(exit) = exit.filter(|_| !stdout_open && !stderr_open) {
+                    break exit;
+                }
+                // Why the lambda must be killed
+                let mut stop = None;
+                select! {
+                    () = tx.closed() => return Err(Abort::Cancelled),
+                    () = &mut deadline => {
+                        stop = Some(Kill::Timeout(timeout.unwrap_or_default()));
+                    },
+                    result = &mut exited, if !ended => {
+                        ended = true;
+                        match result {
+                            Err(e) => return Err(abort(&tx, e).await),
+                            Ok(result) => exit = Some(result),
+                        }
+                    },
+                    result = &mut feed, if feeding => {
+                        feeding = false;
+                        match result {
+                            Err(e) => return Err(abort(&tx, e).await),
+                            Ok(exceeded) => stop = exceeded,
+                        }
+                    }
+                    n = stdout.read(&mut stdout_buf), if stdout_open => {
+                        let n = match n {
+                            Err(e) => return Err(abort(&tx, e).await),
+                            Ok(n) => n,
+                        };
+                        stdout_open = n != 0;
+                        let mut data = stdout_buf[..n].to_vec();
+                        if let Some(mut head) = typed_head.take() {
+                            head.extend_from_slice(&data);
+                            let Some((content_type, start)) = reported_content_type(&head, n == 0) else {
+                                typed_head = Some(head);
+                                continue;
+                            };
+                            if let Some(content_type) = content_type {
+                                tx.send(Ok(Event::ContentType(content_type))).await?;
+                            }
+                            data = head.split_off(start);
+                        }
+                        let (chunk, exceeded) = take_budget(&data, &mut output_len, max_output);
+                        send_output(&tx, Stream::Stdout, chunk).await?;
+                        if exceeded {
+                            stop = Some(Kill::OutputExceeded(max_output.unwrap_or_default()));
+                        }
+                    }
+                    n = stderr.read(&mut stderr_buf), if stderr_open => {
+                        let n = match n {
+                            Err(e) => return Err(abort(&tx, e).await),
+                            Ok(n) => n,
+                        };
+                        stderr_open = n != 0;
+                        // Logged output counts too, it mustn't flood the server logs
+                        let (chunk, exceeded) = take_budget(&stderr_buf[..n], &mut output_len, max_output);
+                        match log_stderr {
+                            true => log_chunk(&name, &mut stderr_line, chunk, n == 0),
+                            false => send_output(&tx, Stream::Stderr, chunk).await?,
+                        }
+                        if exceeded {
+                            stop = Some(Kill::OutputExceeded(max_output.unwrap_or_default()));
+                        }
+                    }
+                }
+                if let Some(reason) = stop {
+                    // Also kills the descendants of a lambda which already ended
+                    if let Err(e) = kill_group(pid) {
+                        warn!("Failed to kill lambda {name}: {e}");
+                    }
+                    scope.kill().await;
+                    let killed = match exit {
+                        Some(exit) => Ok(exit),
+                        None => (&mut exited).await,
+                    };
+                    ended = true;
+                    warn!("Lambda {name} killed: {reason}");
+                    tx.send(Ok(Event::Killed(reason))).await?;
+                    match killed {
+                        Err(e) => return Err(abort(&tx, e).await),
+                        Ok(killed) => break killed,
+                    }
+                }
+            };
+            log_lines(&name, &mut stderr_line, true);
+            if scope.oom_killed() {
+                warn!("Lambda {name} was killed for running out of memory");
+                tx.send(Ok(Event::Killed(Kill::OutOfMemory))).await?;
+            }
+            tx.send(Ok(Event::Exit(exit))).await?;
+            Ok(())
+        };
+
+        if let Err(aborted) = streamed.await {
+            if let Err(e) = kill_group(pid) {
+                warn!("Failed to kill aborted lambda {name}: {e}");
+            }
+            scope.kill().await;
+            match aborted {
+                Abort::Cancelled => {
+                    info!("Lambda {name} invocation cancelled: client disconnected");
+                }
+                Abort::Failed => warn!("Lambda {name} invocation aborted on an IO error"),
+            }
+        }
+        // Reaped last, its group can't be reused while it is signalled above
+        if let Err(e) = child.wait().await {
+            warn!("Failed to reap lambda {name}: {e}");
+        }
+        // Release the sandbox resources once the child is gone
+        std::mem::drop(scope);
+    });
+
+    Ok(rx)
+}
+
+/// Why an invocation stopped before its exit was sent, the lambda is then killed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Abort {
+    /// The receiver was dropped, e.g. the client went away
+    Cancelled,
+    /// An IO failed, the error was sent
+    Failed,
+}
+
+impl<T> From<mpsc::error::SendError<T>> for Abort {
+    fn from(_: mpsc::error::SendError<T>) -> Self {
+        Self::Cancelled
+    }
+}
+
+/// Send the error `e` which aborts the invocation
+async fn abort(tx: &mpsc::Sender<io::Result<Event>>, e: io::Error) -> Abort {
+    match tx.send(Err(e)).await {
+        Ok(()) => Abort::Failed,
+        Err(_) => Abort::Cancelled,
+    }
+}
+
+/// Send a chunk of output, empty ones are skipped
+async fn send_output(
+    tx: &mpsc::Sender<io::Result<Event>>,
+    stream: Stream,
+    data: &[u8],
+) -> Result<(), mpsc::error::SendError<io::Result<Event>>> {
+    match data.is_empty() {
+        true => Ok(()),
+        false => tx.send(Ok(Event::Output(stream, data.to_vec()))).await,
+    }
+}
+
+/// Content type reported by the first line of `head`, and where the output starts after it.
+/// `None` while the line may still come, output without it is kept whole
+fn reported_content_type(head: &[u8], eof: bool) -> Option<(Option<String>, usize)> {
+    let Some(end) = head.iter().position(|b| *b == b'\n') else {
+        let prefix = &head[..head.len().min(CONTENT_TYPE_LINE.len())];
+        let waiting =
+            !eof && head.len() <= MAX_CONTENT_TYPE_LINE && CONTENT_TYPE_LINE.starts_with(prefix);
+        return (!waiting).then_some((None, 0));
+    };
+    match head[..end].strip_prefix(CONTENT_TYPE_LINE) {
+        Some(content_type) => Some((String::from_utf8(content_type.to_vec()).ok(), end + 1)),
+        None => Some((None, 0)),
+    }
+}
+
+/// Cut `chunk` to what is left of the `max` bytes budget once `used` are spent,
+/// also tells whether the budget is exceeded
+fn take_budget<'a>(chunk: &'a [u8], used: &mut u64, max: Option<u64>) -> (&'a [u8], bool) {
+    let len = u64::try_from(chunk.len()).unwrap_or(u64::MAX);
+    *used = used.saturating_add(len);
+    match max {
+        Some(max) if *used > max => {
+            let left = len.saturating_sub(*used - max);
+            (&chunk[..usize::try_from(left).unwrap_or_default()], true)
+        }
+        _ => (chunk, false),
+    }
+}
+
+/// Add a `chunk` of a lambda stderr to its incomplete `line` and log the lines it completes,
+/// the rest too at `eof` or once it reaches `MAX_LOG_LINE` bytes
+fn log_chunk(name: &str, line: &mut Vec<u8>, chunk: &[u8], eof: bool) {
+    line.extend_from_slice(chunk);
+    log_lines(name, line, eof || line.len() >= MAX_LOG_LINE);
+}
+
+/// Log the complete lines of a lambda stderr, the rest too when `flush`
+fn log_lines(name: &str, buffer: &mut Vec<u8>, flush: bool) {
+    let end = match flush {
+        true => buffer.len(),
+        false => buffer.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1),
+    };
+    for line in buffer.drain(..end).collect::<Vec<_>>().split(|b| *b == b'\n') {
+        if !line.is_empty() {
+            info!("Lambda {name} stderr: {}", String::from_utf8_lossy(line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sandbox::{Host, Sandbox, SandboxKind, Trait as _};
+    use std::process::Stdio;
+
+    /// Run `prg` of `/bin` in a host sandbox, its pid and its events
+    fn invoke(
+        prg: &str,
+        args: &[&str],
+        options: Options,
+    ) -> (u32, mpsc::Receiver<io::Result<Event>>) {
+        let sandbox = Sandbox::from(SandboxKind::Host(Host("/bin".to_string())));
+        let (mut cmd, scope) = sandbox.prepare_spawn(prg, args, &[]).unwrap();
+        let child = cmd
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let pid = child.id().unwrap();
+        let events =
+            run(prg.to_string(), child, scope, Instant::now(), tokio::io::empty(), options);
+        (pid, events.unwrap())
+    }
+
+    #[tokio::test]
+    async fn lambdas_are_killed_at_their_deadline() {
+        let options = Options { timeout: Some(1), ..Options::default() };
+        let started = Instant::now();
+        let (_, mut events) = invoke("sleep", &["30"], options);
+        let mut killed = None;
+        let exit = loop {
+            match events.recv().await.unwrap().unwrap() {
+                Event::Killed(kill) => killed = Some(kill),
+                Event::Exit(exit) => break exit,
+                _ => {}
+            }
+        };
+        assert_eq!(killed, Some(Kill::Timeout(1)));
+        assert_eq!(exit.signal, Some(libc::SIGKILL));
+        assert!(started.elapsed() < Duration::from_secs(10), "{:?}", started.elapsed());
+        assert!(events.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn logged_stderr_counts_against_the_output_cap() {
+        let options = Options { max_output: Some(1000), log_stderr: true, ..Options::default() };
+        let (_, mut events) = invoke("sh", &["-c", "yes >&2"], options);
+        let mut killed = None;
+        while let Some(event) = events.recv().await {
+            match event.unwrap() {
+                Event::Killed(kill) => killed = Some(kill),
+                Event::Output(stream, data) => panic!("{stream:?} output {data:?}"),
+                _ => {}
+            }
+        }
+        assert_eq!(killed, Some(Kill::OutputExceeded(1000)));
+    }
+
+    #[test]
+    fn logged_lines_are_bounded() {
+        let mut line = Vec::new();
+        log_chunk("test", &mut line, b"a\nb", false);
+        assert_eq!(line, b"b");
+        for _ in 0..MAX_LOG_LINE / 64 {
+            log_chunk("test", &mut line, &[b'c'; 128], false);
+            assert!(line.len() < MAX_LOG_LINE);
+        }
+        log_chunk("test", &mut line, b"d", true);
+        assert!(line.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lambdas_are_killed_when_the_receiver_is_dropped() {
+        let (pid, events) = invoke("sleep", &["30"], Options::default());
+        std::mem::drop(events);
+        // Gone once killed and reaped
+        let started = Instant::now();
+        while std::path::Path::new(&format!("/proc/{pid}")).exists() {
+            assert!(started.elapsed() < Duration::from_secs(5), "lambda still running");
+            sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[test]
+    fn budget_cuts_the_chunk_crossing_it() {
+        let mut used = 0;
+        assert_eq!(take_budget(b"abc", &mut used, Some(5)), (&b"abc"[..], false));
+        assert_eq!(take_budget(b"de", &mut used, Some(5)), (&b"de"[..], false));
+        assert_eq!(take_budget(b"", &mut used, Some(5)), (&b""[..], false));
+        assert_eq!(take_budget(b"f", &mut used, Some(5)), (&b""[..], true));
+        assert_eq!(take_budget(b"gh", &mut used, Some(5)), (&b""[..], true));
+        assert_eq!(used, 8);
+
+        used = 3;
+        assert_eq!(take_budget(b"defg", &mut used, Some(5)), (&b"de"[..], true));
+        used = 0;
+        assert_eq!(take_budget(b"abc", &mut used, Some(0)), (&b""[..], true));
+        used = u64::MAX - 1;
+        assert_eq!(take_budget(b"abc", &mut used, None), (&b"abc"[..], false));
+        assert_eq!(used, u64::MAX);
+    }
+
+    #[test]
+    fn content_type_line_is_waited_for_then_stripped() {
+        let line = b"FREEITW-Content-Type: text/csv\na,b\n";
+        assert_eq!(reported_content_type(line, false), Some((Some("text/csv".to_string()), 31)));
+        assert_eq!(reported_content_type(&line[..10], false), None);
+        assert_eq!(reported_content_type(&line[..25], false), None);
+        assert_eq!(reported_content_type(&line[..25], true), Some((None, 0)));
+        assert_eq!(reported_content_type(b"", false), None);
+        assert_eq!(reported_content_type(b"", true), Some((None, 0)));
+        // Output which isn't a content type line is kept whole
+        assert_eq!(reported_content_type(b"FREEITW", true), Some((None, 0)));
+        assert_eq!(reported_content_type(b"a,b", false), Some((None, 0)));
+        assert_eq!(reported_content_type(b"FREEITW\n", false), Some((None, 0)));
+        let long = [CONTENT_TYPE_LINE, &[b'x'; MAX_CONTENT_TYPE_LINE]].concat();
+        assert_eq!(reported_content_type(&long, false), Some((None, 0)));
+        let invalid = [CONTENT_TYPE_LINE, b"\xff\n"].concat();
+        assert_eq!(reported_content_type(&invalid, false), Some((None, 24)));
+    }
+}
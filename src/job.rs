@@ -0,0 +1,169 @@
+use serde::Serialize;
+use std::io;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+use crate::format::Stream;
+use crate::invocation::{failure, Event, Kill};
+use crate::process::Exit;
+
+/// How long a finished job and its output are kept
+pub const RETENTION: Duration = Duration::from_hours(1);
+/// Most jobs kept at once, queued, running or retained
+pub const MAX_JOBS: usize = 1024;
+/// Most bytes captured of each output stream of a job
+pub const MAX_CAPTURE: usize = 16 << 20;
+/// Input cap of a job when the lambda sets none, its input is held until it starts
+pub const MAX_INPUT: u64 = 16 << 20;
+
+/// Lifecycle of an asynchronous invocation
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Waiting for a free slot
+    Queued,
+    /// The lambda is running
+    Running,
+    /// The lambda exited with code 0
+    Succeeded,
+    /// The lambda failed, was killed or couldn't be spawned
+    Failed,
+    /// The lambda was killed at its deadline
+    TimedOut,
+}
+
+/// An asynchronous invocation and its captured output
+#[derive(Debug, Serialize)]
+pub struct Job {
+    /// Job ID
+    pub id: String,
+    /// Invoked lambda
+    pub lambda: String,
+    /// Where it is at
+    pub state: State,
+    /// Why it failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Exit status once ended
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit: Option<Exit>,
+    /// Content type of the stdout, the one reported by a structured result once known
+    #[serde(skip)]
+    pub content_type: Option<String>,
+    /// Some output went past `MAX_CAPTURE` and was dropped
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+    /// When it ended, the first ended jobs are forgotten first
+    #[serde(skip)]
+    pub ended: Option<Instant>,
+    /// Captured stdout
+    #[serde(skip)]
+    pub stdout: Vec<u8>,
+    /// Captured stderr
+    #[serde(skip)]
+    pub stderr: Vec<u8>,
+}
+
+impl Job {
+    /// Create a queued job
+    #[must_use]
+    pub const fn new(id: String, lambda: String, content_type: Option<String>) -> Self {
+        Self {
+            id,
+            lambda,
+            state: State::Queued,
+            error: None,
+            exit: None,
+            content_type,
+            truncated: false,
+            ended: None,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+
+    /// Captured output of a stream
+    #[must_use]
+    pub fn output(&self, stream: Stream) -> &[u8] {
+        match stream {
+            Stream::Stdout => &self.stdout,
+            Stream::Stderr => &self.stderr,
+        }
+    }
+
+    /// Capture a chunk of a stream, up to `MAX_CAPTURE` bytes
+    fn capture(&mut self, stream: Stream, data: &[u8]) {
+        let output = match stream {
+            Stream::Stdout => &mut self.stdout,
+            Stream::Stderr => &mut self.stderr,
+        };
+        let kept = data.len().min(MAX_CAPTURE.saturating_sub(output.len()));
+        output.extend_from_slice(&data[..kept]);
+        self.truncated |= kept < data.len();
+    }
+}
+
+/// Lock a job, it is only ever updated field by field so a poisoned one is still consistent
+pub fn lock(job: &Mutex<Job>) -> std::sync::MutexGuard<'_, Job> {
+    job.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Mark a job failed before its lambda could run
+pub fn fail(job: &Mutex<Job>, error: String) {
+    let mut job = lock(job);
+    job.state = State::Failed;
+    job.error = Some(error);
+    job.ended = Some(Instant::now());
+}
+
+/// Capture the events of a running invocation into its job until it ends
+pub async fn capture(job: &Mutex<Job>, mut events: mpsc::Receiver<io::Result<Event>>) {
+    lock(job).state = State::Running;
+    let mut killed = None;
+    while let Some(event) = events.recv().await {
+        let mut job = lock(job);
+        match event {
+            Err(e) => {
+                job.state = State::Failed;
+                job.error = Some(format!("IO: {e}"));
+            }
+            Ok(Event::ContentType(content_type)) => job.content_type = Some(content_type),
+            Ok(Event::Output(stream, data)) => job.capture(stream, &data),
+            Ok(Event::Killed(kill)) => killed = Some(kill),
+            Ok(Event::Exit(exit)) => {
+                job.exit = Some(exit);
+                job.error = failure(killed, &exit);
+                job.state = match (killed, &job.error) {
+                    (Some(Kill::Timeout(_)), _) => State::TimedOut,
+                    (_, Some(_)) => State::Failed,
+                    (_, None) => State::Succeeded,
+                };
+            }
+        }
+    }
+    let mut job = lock(job);
+    if job.state == State::Running {
+        job.state = State::Failed;
+        job.error = Some("Ended without an exit status".to_string());
+    }
+    job.ended = Some(Instant::now());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capture_stops_at_its_cap() {
+        let mut job = Job::new("id".to_string(), "lambda".to_string(), None);
+        job.capture(Stream::Stdout, &vec![b'a'; MAX_CAPTURE - 1]);
+        assert!(!job.truncated);
+        job.capture(Stream::Stdout, b"bc");
+        job.capture(Stream::Stderr, b"err");
+        assert!(job.truncated);
+        assert_eq!(job.stdout.len(), MAX_CAPTURE);
+        assert_eq!(job.stdout.last(), Some(&b'b'));
+        assert_eq!(job.stderr, b"err");
+    }
+}
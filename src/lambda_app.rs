@@ -1,17 +1,36 @@
 use anyhow::Result;
+use axum::http::StatusCode;
 use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::hash::{Hash, Hasher};
 use std::process::Stdio;
 use tokio::process::Child;
 
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 
 use enum_dispatch::enum_dispatch;
 
+use crate::sandbox::Scope;
 use crate::SandboxTrait;
 
+/// `value` with the members of its objects sorted by name, at any depth
+fn canonical(value: Value) -> Value {
+    match value {
+        Value::Object(members) => {
+            let mut members =
+                members.into_iter().map(|(name, v)| (name, canonical(v))).collect::<Vec<_>>();
+            members.sort_by(|(a, _), (b, _)| a.cmp(b));
+            Value::Object(members.into_iter().collect())
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(canonical).collect()),
+        other => other,
+    }
+}
+
 /// Kind of lambda app for now Python or Bash
-#[allow(clippy::module_name_repetitions)]
+#[allow(clippy::module_name_repetitions, reason = "Kind suffix names the dispatch enum")]
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "lowercase")]
 #[enum_dispatch]
@@ -22,27 +41,112 @@ pub enum LambdaAppKind {
     Bash(BashApp),
 }
 
+impl LambdaAppKind {
+    /// Keys of the kinds in a serialized lambda
+    pub const KEYS: [&'static str; 2] = ["py", "bash"];
+}
+
+/// A lambda app with its execution settings
+#[derive(Serialize, Deserialize)]
+pub struct Lambda {
+    /// The code to run
+    #[serde(flatten)]
+    pub app: LambdaAppKind,
+    /// Default execution timeout in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout: Option<u64>,
+    /// Max request body bytes forwarded to the lambda
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_input_bytes: Option<u64>,
+    /// Max stdout and stderr bytes streamed back
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<u64>,
+    /// HTTP status of failed buffered invocations, a 4xx or 5xx
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub failure_status: Option<u16>,
+    /// What the lambda does
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Environment variables of the lambda process
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+impl Lambda {
+    /// Strong entity tag of the lambda, the SHA-256 of its canonical JSON serialization,
+    /// stable across restarts and builds
+    #[must_use]
+    pub fn etag(&self) -> String {
+        let document = canonical(serde_json::to_value(self).unwrap_or_default());
+        format!("\"{:x}\"", Sha256::digest(document.to_string()))
+    }
+
+    /// Whether the settings can be used: an error `failure_status`
+    /// and environment variable names without `=` nor NUL
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        let valid_name = |name: &String| !name.is_empty() && !name.contains(['=', '\0']);
+        self.failure_status().is_some()
+            && self.env.iter().all(|(name, value)| valid_name(name) && !value.contains('\0'))
+    }
+
+    /// Status of failed buffered invocations, `500` by default,
+    /// `None` when the configured one isn't an error status
+    #[must_use]
+    pub fn failure_status(&self) -> Option<StatusCode> {
+        match self.failure_status {
+            None => Some(StatusCode::INTERNAL_SERVER_ERROR),
+            Some(code) => StatusCode::from_u16(code)
+                .ok()
+                .filter(|s| s.is_client_error() || s.is_server_error()),
+        }
+    }
+}
+
 /// Lambda App trait implement spawn to spawnute the lambda kind
 #[enum_dispatch(LambdaAppKind)]
 pub trait Trait {
-    /// Execute lambda
+    /// Execute lambda, the `Scope` must outlive the child
     /// # Errors
     ///     when Child spawn failed
     fn spawn(
         &self,
         sandbox: &impl SandboxTrait,
         params: &[&str],
+        envs: &[(&str, &str)],
         stdin: Stdio,
         stdout: Stdio,
         stderr: Stdio,
-    ) -> Result<Child>;
+    ) -> Result<(Child, Scope)>;
+
+    /// Name of the field holding the code of the lambda, and the code
+    fn code(&self) -> (&'static str, &str);
+
+    /// Whether the lambda output is a serialized value, see `OutputFormat`
+    fn structured_output(&self) -> bool {
+        false
+    }
+
+    /// Whether the request body is parsed into a `DataFrame`, see `InputFormat`
+    fn dataframe_input(&self) -> bool {
+        false
+    }
 }
 
+/// Python runner calling the entrypoint of a lambda
+const PY_RUNNER: &str = include_str!("py/runner.py");
+
 /// A python lambda
 #[derive(Serialize, Deserialize)]
 pub struct PyApp {
     pycode: String,
+    /// Function called with the request body as JSON kwargs,
+    /// when empty the whole script is run
+    #[serde(default)]
     entrypoint: String,
+    /// Parse the request body into a `DataFrame` given as first argument of the entrypoint
+    #[serde(default)]
+    dataframe: bool,
 }
 
 impl Trait for PyApp {
@@ -50,10 +154,11 @@ impl Trait for PyApp {
         &self,
         sandbox: &impl SandboxTrait,
         params: &[&str],
+        envs: &[(&str, &str)],
         stdin: Stdio,
         stdout: Stdio,
         stderr: Stdio,
-    ) -> Result<Child> {
+    ) -> Result<(Child, Scope)> {
         // make sure it has shebang
         let pycode = "#!/bin/env python3\n".to_string() + &self.pycode;
 
@@ -61,18 +166,43 @@ impl Trait for PyApp {
         let mut hasher = DefaultHasher::new();
         pycode.hash(&mut hasher);
         let hash_value = hasher.finish();
-        let pname = hash_value.to_string() + ".py";
+        let mut pname = hash_value.to_string() + ".py";
         sandbox.injest(pycode.as_bytes(), &pname)?;
 
+        // generate the wrapper calling the entrypoint
+        if !self.entrypoint.is_empty() {
+            // JSON strings are valid python string literals
+            let runner = format!(
+                "#!/bin/env python3\n{PY_RUNNER}\nfreeitw_run(os.path.join(os.path.dirname(__file__), {}), {})\n",
+                serde_json::to_string(&pname)?,
+                serde_json::to_string(&self.entrypoint)?,
+            );
+            let mut runner_hasher = DefaultHasher::new();
+            runner.hash(&mut runner_hasher);
+            pname = runner_hasher.finish().to_string() + "_run.py";
+            sandbox.injest(runner.as_bytes(), &pname)?;
+        }
+
         // spawn
-        Ok(sandbox
-            .prepare_spawn(&pname)
-            .envs([("PATH", "/tmp/freeitw_wd/bin:/sbin:/bin"), ("VIRTUAL_ENV", "/tmp/freeitw_wd")])
-            .args(params)
-            .stdin(stdin)
-            .stdout(stdout)
-            .stderr(stderr)
-            .spawn()?)
+        let envs = [("PATH", "/tmp/freeitw_wd/bin:/sbin:/bin"), ("VIRTUAL_ENV", "/tmp/freeitw_wd")]
+            .into_iter()
+            .chain(envs.iter().copied())
+            .collect::<Vec<_>>();
+        let (mut cmd, scope) = sandbox.prepare_spawn(&pname, params, &envs)?;
+        let child = cmd.stdin(stdin).stdout(stdout).stderr(stderr).spawn()?;
+        Ok((child, scope))
+    }
+
+    fn code(&self) -> (&'static str, &str) {
+        ("pycode", &self.pycode)
+    }
+
+    fn structured_output(&self) -> bool {
+        !self.entrypoint.is_empty()
+    }
+
+    fn dataframe_input(&self) -> bool {
+        self.dataframe && !self.entrypoint.is_empty()
     }
 }
 
@@ -96,10 +226,11 @@ impl Trait for BashApp {
         &self,
         sandbox: &impl SandboxTrait,
         params: &[&str],
+        envs: &[(&str, &str)],
         stdin: Stdio,
         stdout: Stdio,
         stderr: Stdio,
-    ) -> Result<Child> {
+    ) -> Result<(Child, Scope)> {
         // make sure it has shebang
         let script = "#!/bin/env bash\n".to_string() + &self.script;
 
@@ -111,12 +242,33 @@ impl Trait for BashApp {
         sandbox.injest(script.as_bytes(), &pname)?;
 
         // spawn
-        Ok(sandbox
-            .prepare_spawn(&pname)
-            .args(params)
-            .stdin(stdin)
-            .stdout(stdout)
-            .stderr(stderr)
-            .spawn()?)
+        let (mut cmd, scope) = sandbox.prepare_spawn(&pname, params, envs)?;
+        let child = cmd.stdin(stdin).stdout(stdout).stderr(stderr).spawn()?;
+        Ok((child, scope))
+    }
+
+    fn code(&self) -> (&'static str, &str) {
+        ("script", &self.script)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn etag_is_a_digest_independent_of_member_order() {
+        let lambda: Lambda = serde_json::from_value(json!({
+            "bash": { "script": "echo hi" },
+            "env": { "B": "2", "A": "1" },
+            "timeout": 3,
+        }))
+        .unwrap();
+        let document =
+            json!({ "timeout": 3, "env": { "A": "1", "B": "2" }, "bash": { "script": "echo hi" } });
+        let expected = format!("\"{:x}\"", Sha256::digest(canonical(document).to_string()));
+        assert_eq!(lambda.etag(), expected);
+        assert_eq!(lambda.etag().len(), 66);
     }
 }
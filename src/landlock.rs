@@ -0,0 +1,226 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::OpenOptionsExt;
+use tokio::process::Command;
+
+/// `landlock_create_ruleset` flag returning the ABI version
+const CREATE_RULESET_VERSION: u32 = 1;
+/// `landlock_add_rule` rule type
+const RULE_PATH_BENEATH: libc::c_int = 1;
+
+/// Filesystem accesses of the first Landlock ABI
+const ACCESS_FS_EXECUTE: u64 = 1;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+const ACCESS_FS_V1: u64 = (1 << 13) - 1;
+const ACCESS_FS_READ: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+/// Filesystem accesses added by later ABIs
+const ACCESS_FS_REFER: u64 = 1 << 13;
+const ACCESS_FS_TRUNCATE: u64 = 1 << 14;
+const ACCESS_FS_IOCTL_DEV: u64 = 1 << 15;
+
+/// Filesystem accesses the kernel `abi` handles, the others can't be denied
+fn access_fs(abi: i64) -> u64 {
+    match abi {
+        ..=1 => ACCESS_FS_V1,
+        2 => ACCESS_FS_V1 | ACCESS_FS_REFER,
+        3 | 4 => ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE,
+        5.. => ACCESS_FS_V1 | ACCESS_FS_REFER | ACCESS_FS_TRUNCATE | ACCESS_FS_IOCTL_DEV,
+    }
+}
+
+/// `struct landlock_ruleset_attr`
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+/// `struct landlock_path_beneath_attr`
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+/// Landlock filesystem rules, every access outside of them is denied
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Rules {
+    /// Paths readable and executable, the system binary and library dirs by default
+    #[serde(default = "system_dirs")]
+    pub read: Vec<String>,
+    /// Paths fully accessible
+    #[serde(default)]
+    pub write: Vec<String>,
+}
+
+/// Paths a python lambda needs to read besides the venv in the working directory
+fn system_dirs() -> Vec<String> {
+    ["/usr", "/lib", "/lib64", "/bin", "/dev/urandom"].into_iter().map(String::from).collect()
+}
+
+impl Rules {
+    /// Build the ruleset in the parent, `workdir` and the python install of its venv
+    /// are readable and `scratch` writable on top of the rules
+    /// # Errors
+    ///     when Landlock isn't supported or a path can't be opened
+    pub fn ruleset(&self, workdir: &str, scratch: &str) -> Result<OwnedFd> {
+        let (ruleset, handled) = create_ruleset()?;
+        let base = venv_base(workdir);
+        let read = self.read.iter().map(String::as_str).chain([workdir]).chain(base.as_deref());
+        let read = read.map(|p| (p, ACCESS_FS_READ));
+        let write = self.write.iter().map(String::as_str).chain([scratch]);
+        for (path, access) in read.chain(write.map(|p| (p, handled))) {
+            add_rule(&ruleset, path, access)?;
+        }
+        Ok(ruleset)
+    }
+}
+
+/// Prefix of the python install a venv in `workdir` runs, from the `home` of its `pyvenv.cfg`
+fn venv_base(workdir: &str) -> Option<String> {
+    let config = fs::read_to_string(format!("{workdir}/pyvenv.cfg")).ok()?;
+    let home = config.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        (key.trim() == "home").then(|| value.trim().to_string())
+    })?;
+    let base = std::path::Path::new(&home).parent()?;
+    Some(base.to_string_lossy().into_owned())
+}
+
+/// Create an empty ruleset handling every filesystem access of the kernel ABI,
+/// also tells which ones
+fn create_ruleset() -> Result<(OwnedFd, u64)> {
+    // SAFETY: a null attr with the version flag only queries the ABI
+    #[allow(unsafe_code, reason = "no landlock wrapper in libc")]
+    let abi = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            std::ptr::null::<RulesetAttr>(),
+            0,
+            CREATE_RULESET_VERSION,
+        )
+    };
+    if abi < 1 {
+        return Err(anyhow!("Landlock is not supported: {}", io::Error::last_os_error()));
+    }
+
+    // Newer accesses would be refused as unknown by older kernels
+    let handled = access_fs(abi);
+    let attr = RulesetAttr { handled_access_fs: handled };
+    // SAFETY: attr is a valid landlock_ruleset_attr of the given size
+    #[allow(unsafe_code, reason = "no landlock wrapper in libc")]
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_create_ruleset,
+            &raw const attr,
+            std::mem::size_of::<RulesetAttr>(),
+            0,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let fd = i32::try_from(fd)?;
+    // SAFETY: the fd was just returned by the kernel and is owned by nobody else
+    #[allow(unsafe_code, reason = "taking ownership of a raw fd")]
+    let ruleset = unsafe { OwnedFd::from_raw_fd(fd) };
+    Ok((ruleset, handled))
+}
+
+/// Allow `access` beneath `path`, missing paths are skipped
+fn add_rule(ruleset: &OwnedFd, path: &str, access: u64) -> Result<()> {
+    let parent = match fs::File::options().read(true).custom_flags(libc::O_PATH).open(path) {
+        Ok(parent) => parent,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    // Directory only rights can't be granted on a file
+    let access = match parent.metadata()?.is_dir() {
+        true => access,
+        false => access & (ACCESS_FS_EXECUTE | ACCESS_FS_WRITE_FILE | ACCESS_FS_READ_FILE),
+    };
+    let attr = PathBeneathAttr { allowed_access: access, parent_fd: parent.as_raw_fd() };
+    // SAFETY: attr is a valid landlock_path_beneath_attr and both fds are open
+    #[allow(unsafe_code, reason = "no landlock wrapper in libc")]
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_landlock_add_rule,
+            ruleset.as_raw_fd(),
+            RULE_PATH_BENEATH,
+            &raw const attr,
+            0,
+        )
+    };
+    match ret {
+        0 => Ok(()),
+        _ => Err(anyhow!("Landlock rule on {path}: {}", io::Error::last_os_error())),
+    }
+}
+
+/// Restrict the spawned process with `ruleset` right before exec
+pub fn restrict_on_exec(cmd: &mut Command, ruleset: &OwnedFd) {
+    let fd = ruleset.as_raw_fd();
+    // SAFETY: the closure only calls prctl(2) and landlock_restrict_self(2),
+    // the caller keeps the ruleset open until the spawn
+    #[allow(unsafe_code, reason = "the process must be restricted between fork and exec")]
+    let _ = unsafe {
+        cmd.pre_exec(move || {
+            // Required to restrict itself without CAP_SYS_ADMIN
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0
+                || libc::syscall(libc::SYS_landlock_restrict_self, fd, 0) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    #[test]
+    fn later_abis_handle_more_accesses() {
+        assert_eq!(access_fs(1), ACCESS_FS_V1);
+        assert_eq!(access_fs(2) & ACCESS_FS_REFER, ACCESS_FS_REFER);
+        assert_eq!(access_fs(2) & ACCESS_FS_TRUNCATE, 0);
+        assert_eq!(access_fs(3) & ACCESS_FS_TRUNCATE, ACCESS_FS_TRUNCATE);
+        assert_eq!(access_fs(7), (1 << 16) - 1);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a kernel with Landlock enabled"]
+    async fn default_rules_only_open_the_system_dirs_and_the_invocation_dirs() {
+        let dir =
+            std::env::temp_dir().join(format!("freeitw_test_landlock_{}", std::process::id()));
+        let (workdir, scratch) = (dir.join("wd"), dir.join("scratch"));
+        fs::create_dir_all(&workdir).unwrap();
+        fs::create_dir_all(&scratch).unwrap();
+        fs::write(workdir.join("pyvenv.cfg"), "home = /usr/bin\n").unwrap();
+        assert_eq!(venv_base(&workdir.to_string_lossy()).as_deref(), Some("/usr"));
+        let rules = Rules { read: system_dirs(), write: Vec::new() };
+        let ruleset =
+            rules.ruleset(&workdir.to_string_lossy(), &scratch.to_string_lossy()).unwrap();
+
+        // Whether each access is denied
+        let script = "read -r l </etc/passwd; echo $?; read -r l <wd/pyvenv.cfg; echo $?; \
+            cp wd/pyvenv.cfg scratch/; echo $?; cp wd/pyvenv.cfg wd/copy; echo $?";
+        let mut cmd = Command::new("/bin/sh");
+        let _ =
+            cmd.args(["-c", script]).current_dir(&dir).stdout(Stdio::piped()).stderr(Stdio::null());
+        restrict_on_exec(&mut cmd, &ruleset);
+        let out = cmd.output().await.unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        let denied = String::from_utf8_lossy(&out.stdout)
+            .lines()
+            .map(|code| code != "0")
+            .collect::<Vec<_>>();
+        assert_eq!(denied, [true, false, false, true], "{out:?}");
+    }
+}
@@ -53,17 +53,18 @@
 //     clippy::multiple_crate_versions, // check from time to time
     clippy::wildcard_dependencies,
 )]
-#![allow(clippy::match_bool)]
+#![allow(clippy::match_bool, reason = "match on bool reads better than if/else")]
 
 use anyhow::Result;
-use axum::routing::{get, post};
+use axum::routing::{get, post, put};
 use axum::Router;
-use log::info;
+use log::{info, warn};
 use std::process::Stdio;
 use std::{
     collections::HashMap,
     sync::{Arc, RwLock},
 };
+use tokio::sync::Semaphore;
 use tower_http::trace::TraceLayer;
 use tracing::Level;
 use tracing_subscriber::prelude::*;
@@ -77,18 +78,60 @@ mod lambda_app;
 /// http Pagination
 mod pagination;
 
+/// Data formats negotiation
+mod format;
+
 /// Sandboxing
 mod sandbox;
 
+/// cgroup v2 resource limits
+mod cgroup;
+
+/// POSIX resource limits
+mod rlimit;
+
+/// Native linux namespaces sandbox
+mod namespace;
+
+/// seccomp-bpf syscall filters
+mod seccomp;
+
+/// Landlock filesystem restrictions
+mod landlock;
+
+/// Network namespaces and egress proxy
+mod network;
+
+/// OCI runtime bundles
+mod oci;
+
+/// Child processes handling
+mod process;
+
+/// Lambda invocations IO and limits
+mod invocation;
+
+/// Asynchronous invocations
+mod job;
+
+/// Persistent lambdas and sandboxes registry
+mod store;
+
+/// Lambda versions and aliases
+mod history;
+
 mod api;
 
 use api::{
-    lambda_delete, lambda_exec, lambda_get, lambdas_index, lambdas_insert, sandboxs_index, AppState,
+    alias_delete, alias_put, invocation_get, invocation_output, lambda_delete, lambda_diff,
+    lambda_exec, lambda_get, lambda_invoke, lambda_patch, lambda_rollback, lambda_versions,
+    lambdas_index, lambdas_insert, sandbox_delete, sandbox_get, sandboxs_index, sandboxs_insert,
+    AppState,
 };
 use lambda_app::{BashApp, Trait as LambdaTrait};
-use sandbox::{
-    default_sandboxs, Host as SandboxHost, SandboxKind as Sandbox, Trait as SandboxTrait,
-};
+use sandbox::{default_sandboxs, Host as SandboxHost, Sandbox, SandboxKind, Trait as SandboxTrait};
+use std::path::Path;
+use store::{Directory as StoreDirectory, Memory as StoreMemory, StoreKind};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -109,7 +152,7 @@ async fn main() -> Result<()> {
     std::fs::create_dir_all(&wd)?;
 
     // Initialize the sandbox host
-    let init_host_sb = SandboxHost(wd.clone());
+    let init_host_sb = Sandbox::from(SandboxKind::Host(SandboxHost(wd.clone())));
     // Create a new BashApp instance with the initialization script
     let init = BashApp::new(
         r#"#!/bin/env bash
@@ -119,42 +162,103 @@ mkdir -p "$WD"
 
 command -v python3 &>/dev/null || exit 127
 command -v pip3 &>/dev/null || exit 127
-command -v bwrap &>/dev/null || exit 127
+command -v bwrap &>/dev/null || echo "bwrap not found, bwrap sandbox disabled" >&2
 
 python3 -m venv "$WD"
 source "$WD"/bin/activate
-pip3 install pandas
+pip3 install pandas pyarrow
     "#,
     );
 
-    let host_wd = wd.clone();
-    let bwrap_wd = wd.clone();
-
     info!("Setup bwrap sandbox...");
     // Spawn the initialization script
-    let init =
-        init.spawn(&init_host_sb, &[&wd], Stdio::inherit(), Stdio::inherit(), Stdio::inherit())?;
+    let (init, init_scope) = init.spawn(
+        &init_host_sb,
+        &[&wd],
+        &[],
+        Stdio::inherit(),
+        Stdio::inherit(),
+        Stdio::inherit(),
+    )?;
     let out = init.wait_with_output().await?;
+    std::mem::drop(init_scope);
     if !out.status.success() {
         return Err(anyhow::anyhow!(out.status));
     }
     // Create the default sandboxes
-    let (host_sb, bwrap_sb) = default_sandboxs(host_wd, bwrap_wd);
+    // The overlay one needs a rootfs image directory
+    let rootfs = std::env::var("FREEITW_OVERLAY_ROOTFS").ok();
+    let (host_sb, bwrap_sb, ns_sb, overlay_sb) = default_sandboxs(&wd, rootfs.as_deref());
 
     // Store sandboxes in a HashMap
     let mut sandboxs = HashMap::new();
-    let _ = sandboxs.insert("host".to_string(), Arc::new(Sandbox::Host(host_sb)));
-    let _ = sandboxs.insert("bwrap".to_string(), Arc::new(Sandbox::BubbleWrap(bwrap_sb)));
+    let _ =
+        sandboxs.insert("host".to_string(), Arc::new(Sandbox::from(SandboxKind::Host(host_sb))));
+    if std::path::Path::new("/usr/bin/bwrap").exists() {
+        let _ = sandboxs.insert(
+            "bwrap".to_string(),
+            Arc::new(Sandbox::from(SandboxKind::BubbleWrap(bwrap_sb))),
+        );
+        if let Some(overlay_sb) = overlay_sb {
+            let _ = sandboxs.insert(
+                "overlay".to_string(),
+                Arc::new(Sandbox::from(SandboxKind::Overlay(overlay_sb))),
+            );
+        }
+    }
+    let _ = sandboxs
+        .insert("namespace".to_string(), Arc::new(Sandbox::from(SandboxKind::Namespace(ns_sb))));
 
     // Create shared application state
-    let state = Arc::new(RwLock::new(AppState { lambdas: HashMap::new(), sandboxs }));
+    // Registry storage, a directory of JSON documents unless `FREEITW_STORE=memory`
+    let store_path = match std::env::var_os("FREEITW_STORE") {
+        Some(path) => Some(std::path::PathBuf::from(path)),
+        None => StoreDirectory::default_path(),
+    };
+    let store = match store_path {
+        Some(path) if path.as_os_str() != "memory" => {
+            info!("Registry stored in {}", path.display());
+            StoreKind::from(StoreDirectory::open(path)?)
+        }
+        _ => {
+            warn!("Registry kept in memory only");
+            StoreKind::from(StoreMemory)
+        }
+    };
+    // Paths of the sandboxes created through the API are confined to it
+    let sandbox_root = std::env::var_os("FREEITW_SANDBOX_ROOT")
+        .map_or_else(std::env::temp_dir, std::path::PathBuf::from);
+    // Stored sandboxes take precedence over the default ones
+    sandboxs.extend(stored_sandboxs(&store, &sandbox_root)?);
+    let lambdas = store::load_all(&store, store::LAMBDAS)?;
+    info!("Loaded {} lambdas and {} sandboxes", lambdas.len(), sandboxs.len());
+
+    // Asynchronous invocations are queued past one running per CPU
+    let job_slots = Arc::new(Semaphore::new(std::thread::available_parallelism()?.get()));
+    let state = Arc::new(RwLock::new(AppState {
+        lambdas,
+        sandboxs,
+        jobs: HashMap::new(),
+        job_slots,
+        store: Arc::new(store),
+        registry_writer: Arc::default(),
+        sandbox_root,
+    }));
 
     // Compose the routes
     let app = Router::new()
-        .route("/sandboxs", get(sandboxs_index))
+        .route("/sandboxs", get(sandboxs_index).put(sandboxs_insert))
+        .route("/sandboxs/:name", get(sandbox_get).delete(sandbox_delete))
         .route("/lambdas", get(lambdas_index).put(lambdas_insert))
         .route("/lambdas/:name/exec", post(lambda_exec))
-        .route("/lambdas/:name", get(lambda_get).delete(lambda_delete))
+        .route("/lambdas/:name/invocations", post(lambda_invoke))
+        .route("/invocations/:id", get(invocation_get))
+        .route("/invocations/:id/output", get(invocation_output))
+        .route("/lambdas/:name", get(lambda_get).patch(lambda_patch).delete(lambda_delete))
+        .route("/lambdas/:name/versions", get(lambda_versions))
+        .route("/lambdas/:name/rollback", post(lambda_rollback))
+        .route("/lambdas/:name/diff", get(lambda_diff))
+        .route("/lambdas/:name/aliases/:alias", put(alias_put).delete(alias_delete))
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
@@ -163,3 +267,42 @@ pip3 install pandas
     let listener = tokio::net::TcpListener::bind(":::3000").await?;
     Ok(axum::serve(listener, app).await?)
 }
+
+/// Sandboxes of the `store` whose paths are confined to `sandbox_root`, the others are skipped
+/// # Errors
+///     when the storage can't be read
+fn stored_sandboxs(
+    store: &StoreKind,
+    sandbox_root: &Path,
+) -> Result<HashMap<String, Arc<Sandbox>>> {
+    let mut sandboxs = store::load_all::<Arc<Sandbox>>(store, store::SANDBOXS)?;
+    sandboxs.retain(|name, sandbox| match sandbox.check_paths(sandbox_root) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Skipping stored sandbox {name}: {e}");
+            false
+        }
+    });
+    Ok(sandboxs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store::Trait as _;
+
+    #[test]
+    fn stored_sandboxs_are_confined_to_the_sandbox_root() {
+        let path = std::env::temp_dir().join(format!("freeitw_test_stored_{}", std::process::id()));
+        let store = StoreKind::from(StoreDirectory::open(path.clone()).unwrap());
+        let host = |dir: &str| {
+            let sandbox = Sandbox::from(SandboxKind::Host(SandboxHost(dir.to_string())));
+            serde_json::to_value(sandbox).unwrap()
+        };
+        store.save(store::SANDBOXS, "inside", &host("/srv/lambdas/a")).unwrap();
+        store.save(store::SANDBOXS, "outside", &host("/etc")).unwrap();
+        let sandboxs = stored_sandboxs(&store, Path::new("/srv/lambdas")).unwrap();
+        assert_eq!(sandboxs.keys().collect::<Vec<_>>(), ["inside"]);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,317 @@
+use anyhow::Result;
+use std::ffi::{CStr, CString};
+use std::io;
+use std::path::Path;
+use tokio::process::Command;
+
+/// Device nodes bound from the host into the sandbox `/dev`
+const DEVICES: [&str; 6] = ["null", "zero", "full", "random", "urandom", "tty"];
+
+/// Setup action run in the child, prepared by the parent
+/// so nothing is allocated between fork and exec
+#[derive(Debug)]
+enum Step {
+    /// Create a directory
+    Mkdir(CString),
+    /// Create an empty file to bind a file on
+    Touch(CString),
+    /// Create a symlink, target then link path
+    Symlink(CString, CString),
+    /// Mount a filesystem
+    Mount { source: CString, target: CString, fstype: Option<CString>, flags: libc::c_ulong },
+    /// Make a bind mount read-only
+    RemountRo(CString),
+}
+
+/// Everything needed to enter the namespaces and build the root filesystem
+#[derive(Debug)]
+pub struct Plan {
+    id_maps: IdMaps,
+    root: CString,
+    hostname: CString,
+    steps: Vec<Step>,
+}
+
+/// Convert a path to a C string
+fn cstr<P: AsRef<Path>>(path: P) -> Result<CString> {
+    Ok(CString::new(path.as_ref().as_os_str().as_encoded_bytes())?)
+}
+
+/// Plan the creation of the missing parents of a mount point inside `root`
+fn mkdirs(steps: &mut Vec<Step>, root: &str, path: &Path) -> Result<()> {
+    for dir in path.ancestors().collect::<Vec<_>>().into_iter().rev() {
+        if dir.starts_with(root) && dir != Path::new(root) {
+            steps.push(Step::Mkdir(cstr(dir)?));
+        }
+    }
+    Ok(())
+}
+
+impl Plan {
+    /// Plan a root made of a tmpfs mounted on `root`, `ro_binds` host paths bound read-only
+    /// and `workdir` bound read-only, host paths that don't exist are skipped.
+    /// The process works in the fresh `/tmp` tmpfs, private to the invocation
+    /// # Errors
+    ///     when a path contains a nul byte
+    pub fn new(root: &str, workdir: &str, ro_binds: &[String], hostname: &str) -> Result<Self> {
+        let in_root = |path: &str| Path::new(root).join(path.trim_start_matches('/'));
+
+        let mut steps = vec![Step::Mount {
+            source: cstr("tmpfs")?,
+            target: cstr(root)?,
+            fstype: Some(cstr("tmpfs")?),
+            flags: libc::MS_NOSUID | libc::MS_NODEV,
+        }];
+
+        for (path, fstype, flags) in [
+            ("/tmp", "tmpfs", libc::MS_NOSUID | libc::MS_NODEV),
+            ("/dev", "tmpfs", libc::MS_NOSUID),
+            ("/proc", "proc", libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC),
+        ] {
+            mkdirs(&mut steps, root, &in_root(path))?;
+            steps.push(Step::Mount {
+                source: cstr(fstype)?,
+                target: cstr(in_root(path))?,
+                fstype: Some(cstr(fstype)?),
+                flags,
+            });
+        }
+        for dev in DEVICES {
+            let host = format!("/dev/{dev}");
+            if Path::new(&host).exists() {
+                steps.push(Step::Touch(cstr(in_root(&host))?));
+                steps.push(Step::Mount {
+                    source: cstr(&host)?,
+                    target: cstr(in_root(&host))?,
+                    fstype: None,
+                    flags: libc::MS_BIND,
+                });
+            }
+        }
+        for (target, link) in [
+            ("/proc/self/fd", "/dev/fd"),
+            ("/proc/self/fd/0", "/dev/stdin"),
+            ("/proc/self/fd/1", "/dev/stdout"),
+            ("/proc/self/fd/2", "/dev/stderr"),
+        ] {
+            steps.push(Step::Symlink(cstr(target)?, cstr(in_root(link))?));
+        }
+
+        for path in ro_binds.iter().map(String::as_str).chain([workdir]) {
+            let Ok(meta) = std::fs::metadata(path) else {
+                continue;
+            };
+            let target = in_root(path);
+            if meta.is_dir() {
+                mkdirs(&mut steps, root, &target)?;
+            } else {
+                if let Some(parent) = target.parent() {
+                    mkdirs(&mut steps, root, parent)?;
+                }
+                steps.push(Step::Touch(cstr(&target)?));
+            }
+            steps.push(Step::Mount {
+                source: cstr(path)?,
+                target: cstr(&target)?,
+                fstype: None,
+                flags: libc::MS_BIND | libc::MS_REC,
+            });
+            steps.push(Step::RemountRo(cstr(&target)?));
+        }
+
+        Ok(Self {
+            id_maps: IdMaps::new()?,
+            root: cstr(root)?,
+            hostname: CString::new(hostname)?,
+            steps,
+        })
+    }
+}
+
+/// Effective uid and gid of the server
+#[must_use]
+pub fn effective_ids() -> (libc::uid_t, libc::gid_t) {
+    // SAFETY: geteuid and getegid always succeed
+    #[allow(unsafe_code, reason = "no safe wrapper for geteuid in std")]
+    unsafe {
+        (libc::geteuid(), libc::getegid())
+    }
+}
+
+/// uid and gid maps of a new user namespace, where the server ids are kept
+#[derive(Debug)]
+pub struct IdMaps {
+    uid_map: CString,
+    gid_map: CString,
+}
+
+impl IdMaps {
+    /// Map the effective ids of the server to themselves,
+    /// the only mapping an unprivileged process may write
+    /// # Errors
+    ///     never, the maps hold no nul byte
+    pub fn new() -> Result<Self> {
+        let (uid, gid) = effective_ids();
+        Ok(Self {
+            uid_map: CString::new(format!("{uid} {uid} 1"))?,
+            gid_map: CString::new(format!("{gid} {gid} 1"))?,
+        })
+    }
+
+    /// Write the maps of the user namespace just unshared, `setgroups` must be denied first
+    /// # Safety
+    ///     async-signal-safe, only does syscalls
+    #[allow(unsafe_code, reason = "raw syscalls between fork and exec")]
+    pub unsafe fn write(&self) -> io::Result<()> {
+        write_file(c"/proc/self/setgroups", c"deny")?;
+        write_file(c"/proc/self/uid_map", &self.uid_map)?;
+        write_file(c"/proc/self/gid_map", &self.gid_map)
+    }
+}
+
+/// Turn a libc return value into an `io::Result`
+pub fn check<T: Default + PartialOrd>(ret: T) -> io::Result<T> {
+    match ret < T::default() {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(ret),
+    }
+}
+
+/// Write `content` to the file at `path`
+/// # Safety
+///     async-signal-safe, only does syscalls
+#[allow(unsafe_code, reason = "raw syscalls between fork and exec")]
+pub unsafe fn write_file(path: &CStr, content: &CStr) -> io::Result<()> {
+    let fd = check(libc::open(path.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC))?;
+    let written = check(libc::write(fd, content.as_ptr().cast(), content.count_bytes()));
+    let _ = libc::close(fd);
+    written.map(|_| ())
+}
+
+/// Wait for the sandboxed child and exit with its status
+/// # Safety
+///     async-signal-safe, only does syscalls
+#[allow(unsafe_code, reason = "raw syscalls between fork and exec")]
+unsafe fn wait_and_exit(pid: libc::pid_t) -> ! {
+    // Keep no pipe open, the spawner waits for their end. That includes the copy of the
+    // exec error pipe of std: the spawner reads it until every copy is closed, the grandchild
+    // keeps its own until exec and writes the exec error there, kept open here the spawn
+    // would only return once the sandboxed process exits
+    let _ = libc::syscall(libc::SYS_close_range, 0, libc::c_uint::MAX, 0);
+    let mut status = 0;
+    while libc::waitpid(pid, &raw mut status, 0) < 0 {
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            libc::_exit(1);
+        }
+    }
+    match libc::WIFSIGNALED(status) {
+        true => libc::_exit(128 + libc::WTERMSIG(status)),
+        false => libc::_exit(libc::WEXITSTATUS(status)),
+    }
+}
+
+/// Enter new namespaces and pivot into the planned root, runs between fork and exec
+/// # Safety
+///     async-signal-safe, only does syscalls
+#[allow(unsafe_code, reason = "raw syscalls between fork and exec")]
+unsafe fn enter(plan: &Plan) -> io::Result<()> {
+    let _ = check(libc::unshare(
+        libc::CLONE_NEWUSER
+            | libc::CLONE_NEWNS
+            | libc::CLONE_NEWPID
+            | libc::CLONE_NEWIPC
+            | libc::CLONE_NEWUTS
+            | libc::CLONE_NEWNET,
+    ))?;
+    plan.id_maps.write()?;
+
+    // The new pid namespace applies to children, the grandchild is its init
+    match check(libc::fork())? {
+        0 => {}
+        pid => wait_and_exit(pid),
+    }
+    let _ = check(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL))?;
+
+    let none = c"none".as_ptr();
+    let _ = check(libc::mount(
+        none,
+        c"/".as_ptr(),
+        std::ptr::null(),
+        libc::MS_REC | libc::MS_PRIVATE,
+        std::ptr::null(),
+    ))?;
+    for step in &plan.steps {
+        match step {
+            Step::Mkdir(path) => {
+                if libc::mkdir(path.as_ptr(), 0o755) < 0
+                    && io::Error::last_os_error().kind() != io::ErrorKind::AlreadyExists
+                {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Step::Touch(path) => {
+                let fd = check(libc::open(
+                    path.as_ptr(),
+                    libc::O_WRONLY | libc::O_CREAT | libc::O_CLOEXEC,
+                    0o644,
+                ))?;
+                let _ = libc::close(fd);
+            }
+            Step::Symlink(target, link) => {
+                let _ = check(libc::symlink(target.as_ptr(), link.as_ptr()))?;
+            }
+            Step::Mount { source, target, fstype, flags } => {
+                let fstype = fstype.as_ref().map_or(std::ptr::null(), |f| f.as_ptr());
+                let _ = check(libc::mount(
+                    source.as_ptr(),
+                    target.as_ptr(),
+                    fstype,
+                    *flags,
+                    std::ptr::null(),
+                ))?;
+            }
+            Step::RemountRo(target) => {
+                // Locked flags of the original mount must be kept
+                let mut stat: libc::statvfs = std::mem::zeroed();
+                let _ = check(libc::statvfs(target.as_ptr(), &raw mut stat))?;
+                let kept = stat.f_flag
+                    & (libc::ST_NOSUID
+                        | libc::ST_NODEV
+                        | libc::ST_NOEXEC
+                        | libc::ST_NOATIME
+                        | libc::ST_NODIRATIME
+                        | libc::ST_RELATIME);
+                let _ = check(libc::mount(
+                    none,
+                    target.as_ptr(),
+                    std::ptr::null(),
+                    libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY | kept,
+                    std::ptr::null(),
+                ))?;
+            }
+        }
+    }
+
+    // Swap the root and drop the old one
+    let _ = check(libc::chdir(plan.root.as_ptr()))?;
+    let _ = check(libc::syscall(libc::SYS_pivot_root, c".".as_ptr(), c".".as_ptr()))?;
+    let _ = check(libc::umount2(c".".as_ptr(), libc::MNT_DETACH))?;
+    let _ = check(libc::mount(
+        none,
+        c"/".as_ptr(),
+        std::ptr::null(),
+        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+        std::ptr::null(),
+    ))?;
+
+    let _ = check(libc::sethostname(plan.hostname.as_ptr(), plan.hostname.count_bytes()))?;
+    let _ = check(libc::chdir(c"/tmp".as_ptr()))?;
+    Ok(())
+}
+
+/// Make the spawned process enter the sandbox before exec
+pub fn enter_on_exec(cmd: &mut Command, plan: Plan) {
+    // SAFETY: `enter` only does async-signal-safe syscalls on memory allocated by the parent
+    #[allow(unsafe_code, reason = "namespaces must be entered between fork and exec")]
+    let _ = unsafe { cmd.pre_exec(move || enter(&plan)) };
+}
@@ -0,0 +1,396 @@
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::Ipv4Addr;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream as StdUnixStream;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::process::Command;
+use tokio::task::{JoinHandle, JoinSet};
+
+use crate::namespace::{check, effective_ids, IdMaps};
+
+/// Port the egress proxy listens on, inside the sandbox network namespace
+pub const PROXY_PORT: u16 = 3128;
+
+/// Max size of a proxied request head
+const MAX_HEAD: usize = 8192;
+
+/// Network access of each invocation
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Fresh network namespace without any interface up
+    None,
+    /// Fresh network namespace with only the loopback up
+    Loopback,
+    /// Loopback and an HTTP proxy on `127.0.0.1:3128` reaching only these `host:port`
+    Allowlist(Vec<String>),
+}
+
+/// Everything the child needs to enter its network namespace
+#[derive(Debug)]
+struct Setup {
+    /// uid and gid maps when a user namespace is needed to unshare the network
+    user_ns: Option<IdMaps>,
+    /// Bring the loopback up
+    loopback: bool,
+    /// Socket the proxy listener is sent back on
+    proxy: Option<RawFd>,
+}
+
+/// The egress proxy of an invocation, stopped on drop
+#[derive(Debug)]
+pub struct Proxy {
+    /// Child end of the socket pair, open until the spawn
+    _child_end: StdUnixStream,
+    task: JoinHandle<()>,
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Make the spawned process enter a fresh network namespace before exec.
+/// `privileged` tells the child can unshare it without a new user namespace
+/// # Errors
+///     IO errors creating the proxy socket pair
+pub fn isolate_on_exec(
+    cmd: &mut Command,
+    policy: &Policy,
+    privileged: bool,
+) -> Result<Option<Proxy>> {
+    let user_ns = match privileged || effective_ids().0 == 0 {
+        true => None,
+        false => Some(IdMaps::new()?),
+    };
+
+    let mut proxy = None;
+    let mut setup = Setup { user_ns, loopback: false, proxy: None };
+    match policy {
+        Policy::None => {}
+        Policy::Loopback => setup.loopback = true,
+        Policy::Allowlist(allowed) => {
+            let (parent_end, child_end) = StdUnixStream::pair()?;
+            parent_end.set_nonblocking(true)?;
+            let parent_end = UnixStream::from_std(parent_end)?;
+            setup.loopback = true;
+            setup.proxy = Some(child_end.as_raw_fd());
+            let url = format!("http://127.0.0.1:{PROXY_PORT}");
+            for var in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+                let _ = cmd.env(var, &url);
+            }
+            let task = tokio::spawn(serve(parent_end, Arc::from(allowed.as_slice())));
+            proxy = Some(Proxy { _child_end: child_end, task });
+        }
+    }
+
+    // SAFETY: `enter` only does async-signal-safe syscalls on memory allocated by the parent
+    #[allow(unsafe_code, reason = "the network namespace must be entered between fork and exec")]
+    let _ = unsafe { cmd.pre_exec(move || enter(&setup)) };
+    Ok(proxy)
+}
+
+/// Unshare the network namespace and set it up, runs between fork and exec
+/// # Safety
+///     async-signal-safe, only does syscalls
+#[allow(unsafe_code, reason = "raw syscalls between fork and exec")]
+unsafe fn enter(setup: &Setup) -> io::Result<()> {
+    match &setup.user_ns {
+        Some(id_maps) => {
+            let _ = check(libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNET))?;
+            id_maps.write()?;
+        }
+        None => {
+            let _ = check(libc::unshare(libc::CLONE_NEWNET))?;
+        }
+    }
+    if setup.loopback {
+        loopback_up()?;
+    }
+    if let Some(channel) = setup.proxy {
+        let listener = listen_loopback()?;
+        let sent = send_fd(channel, listener);
+        let _ = libc::close(listener);
+        sent?;
+    }
+    Ok(())
+}
+
+/// Bring the `lo` interface up
+/// # Safety
+///     async-signal-safe, only does syscalls
+#[allow(unsafe_code, reason = "raw syscalls between fork and exec")]
+unsafe fn loopback_up() -> io::Result<()> {
+    let sock = check(libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0))?;
+    let mut req: libc::ifreq = std::mem::zeroed();
+    for (dst, src) in req.ifr_name.iter_mut().zip(c"lo".to_bytes()) {
+        *dst = libc::c_char::from_ne_bytes([*src]);
+    }
+    req.ifr_ifru.ifru_flags =
+        libc::c_short::try_from(libc::IFF_UP | libc::IFF_RUNNING).unwrap_or_default();
+    let up = check(libc::ioctl(sock, libc::SIOCSIFFLAGS, &raw mut req));
+    let _ = libc::close(sock);
+    up.map(|_| ())
+}
+
+/// Listen on `127.0.0.1:PROXY_PORT`
+/// # Safety
+///     async-signal-safe, only does syscalls
+#[allow(unsafe_code, reason = "raw syscalls between fork and exec")]
+unsafe fn listen_loopback() -> io::Result<RawFd> {
+    let sock = check(libc::socket(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0))?;
+    let mut addr: libc::sockaddr_in = std::mem::zeroed();
+    addr.sin_family = libc::sa_family_t::try_from(libc::AF_INET).unwrap_or_default();
+    addr.sin_port = PROXY_PORT.to_be();
+    addr.sin_addr.s_addr = u32::from(Ipv4Addr::LOCALHOST).to_be();
+    let bound = check(libc::bind(
+        sock,
+        (&raw const addr).cast(),
+        libc::socklen_t::try_from(std::mem::size_of::<libc::sockaddr_in>()).unwrap_or_default(),
+    ))
+    .and_then(|_| check(libc::listen(sock, 128)));
+    if let Err(e) = bound {
+        let _ = libc::close(sock);
+        return Err(e);
+    }
+    Ok(sock)
+}
+
+/// Size of a control message carrying one fd, `CMSG_SPACE(sizeof(int))`
+const FD_CMSG_SPACE: usize = 24;
+
+/// Send `fd` over the unix socket `channel`
+/// # Safety
+///     async-signal-safe, only does syscalls
+#[allow(unsafe_code, reason = "raw syscalls between fork and exec")]
+unsafe fn send_fd(channel: RawFd, fd: RawFd) -> io::Result<()> {
+    // u64 keeps the control buffer aligned for cmsghdr
+    let mut control = [0_u64; FD_CMSG_SPACE / 8];
+    let mut byte = [0_u8];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    let mut msg: libc::msghdr = std::mem::zeroed();
+    msg.msg_iov = &raw mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = FD_CMSG_SPACE;
+    let cmsg = libc::CMSG_FIRSTHDR(&raw const msg);
+    (*cmsg).cmsg_level = libc::SOL_SOCKET;
+    (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+    (*cmsg).cmsg_len = libc::CMSG_LEN(4) as usize;
+    libc::CMSG_DATA(cmsg).cast::<RawFd>().write_unaligned(fd);
+    check(libc::sendmsg(channel, &raw const msg, 0)).map(|_| ())
+}
+
+/// Receive a fd sent by `send_fd`
+#[allow(unsafe_code, reason = "no SCM_RIGHTS support in std")]
+fn recv_fd(channel: RawFd) -> io::Result<OwnedFd> {
+    let mut control = [0_u64; FD_CMSG_SPACE / 8];
+    let mut byte = [0_u8];
+    let mut iov = libc::iovec { iov_base: byte.as_mut_ptr().cast(), iov_len: 1 };
+    // SAFETY: msghdr is plain old data, zero is a valid empty header
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &raw mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = FD_CMSG_SPACE;
+    // SAFETY: the buffers outlive the call and their sizes are set in msg
+    let _ = check(unsafe { libc::recvmsg(channel, &raw mut msg, libc::MSG_CMSG_CLOEXEC) })?;
+    // SAFETY: msg was filled by recvmsg, the header is checked before its data is read
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&raw const msg);
+        if cmsg.is_null() || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "no fd received"));
+        }
+        Ok(OwnedFd::from_raw_fd(libc::CMSG_DATA(cmsg).cast::<RawFd>().read_unaligned()))
+    }
+}
+
+/// Wait for the listener of the sandbox and proxy its connections
+async fn serve(channel: UnixStream, allowed: Arc<[String]>) {
+    let listener = loop {
+        if let Err(e) = channel.readable().await {
+            warn!("Proxy channel failed: {e}");
+            return;
+        }
+        match channel.try_io(tokio::io::Interest::READABLE, || recv_fd(channel.as_raw_fd())) {
+            Ok(fd) => break std::net::TcpListener::from(fd),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            Err(e) => {
+                warn!("Proxy listener not received: {e}");
+                return;
+            }
+        }
+    };
+    let listener =
+        match listener.set_nonblocking(true).and_then(|()| TcpListener::from_std(listener)) {
+            Ok(listener) => listener,
+            Err(e) => {
+                warn!("Proxy listener unusable: {e}");
+                return;
+            }
+        };
+    // Dropping the set on abort closes every connection
+    let mut connections = JoinSet::new();
+    while let Ok((conn, _)) = listener.accept().await {
+        let allowed = Arc::clone(&allowed);
+        let _ = connections.spawn(async move {
+            if let Err(e) = forward(conn, allowed).await {
+                info!("Proxy connection closed: {e}");
+            }
+        });
+    }
+}
+
+/// Destination `host:port` of a proxied request, with the path a plain HTTP request
+/// is sent to in origin-form, CONNECT tunnels to its target.
+/// `None` for plain HTTP targets not in absolute-form, they don't name a destination
+fn destination<'a>(method: &str, target: &'a str) -> Option<(String, Option<&'a str>)> {
+    if method == "CONNECT" {
+        return Some((target.to_string(), None));
+    }
+    let rest = target.strip_prefix("http://").filter(|r| !r.is_empty() && !r.starts_with('/'))?;
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+    let path = if path.is_empty() { "/" } else { path };
+    let dest = match authority.contains(':') {
+        true => authority.to_string(),
+        false => format!("{authority}:80"),
+    };
+    Some((dest, Some(path)))
+}
+
+/// Forward one proxied connection if its destination is allowed
+async fn forward(conn: TcpStream, allowed: Arc<[String]>) -> io::Result<()> {
+    let mut client = BufReader::new(conn);
+    let mut head = String::new();
+    while !head.ends_with("\r\n\r\n") {
+        // Also caps a single line without newline
+        let left = MAX_HEAD.saturating_sub(head.len());
+        if left == 0 {
+            return client
+                .write_all(
+                    b"HTTP/1.1 431 Request Header Fields Too Large\r\ncontent-length: 0\r\n\r\n",
+                )
+                .await;
+        }
+        let left = u64::try_from(left).unwrap_or(u64::MAX);
+        if (&mut client).take(left).read_line(&mut head).await? == 0 {
+            return Ok(());
+        }
+    }
+    let (request_line, headers) = head.split_once("\r\n").unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = (
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+        parts.next().unwrap_or_default(),
+    );
+
+    let Some((dest, path)) = destination(method, target) else {
+        return client.write_all(b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\n\r\n").await;
+    };
+    let request = path.map(|path| format!("{method} {path} {version}\r\n{headers}"));
+    if !allowed.contains(&dest) {
+        warn!("Proxy denied egress to {dest}");
+        return client.write_all(b"HTTP/1.1 403 Forbidden\r\ncontent-length: 0\r\n\r\n").await;
+    }
+
+    let mut upstream = match TcpStream::connect(&dest).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            warn!("Proxy failed to reach {dest}: {e}");
+            return client
+                .write_all(b"HTTP/1.1 502 Bad Gateway\r\ncontent-length: 0\r\n\r\n")
+                .await;
+        }
+    };
+    match request {
+        Some(request) => upstream.write_all(request.as_bytes()).await?,
+        None => client.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").await?,
+    }
+    info!("Proxy forwarding to {dest}");
+    tokio::io::copy_bidirectional(&mut client, &mut upstream).await.map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn destinations_are_named_by_absolute_targets() {
+        let dest = |target| destination("GET", target);
+        assert_eq!(dest("http://example.com"), Some(("example.com:80".to_string(), Some("/"))));
+        assert_eq!(
+            dest("http://example.com:8080/a?b"),
+            Some(("example.com:8080".to_string(), Some("/a?b")))
+        );
+        assert_eq!(dest("/index.html"), None);
+        assert_eq!(dest("http:///index.html"), None);
+        assert_eq!(dest("https://example.com/"), None);
+        assert_eq!(
+            destination("CONNECT", "example.com:443"),
+            Some(("example.com:443".to_string(), None))
+        );
+    }
+
+    /// Proxy one connection sending `request`, the response and what the upstream got
+    async fn proxy(request: &[u8], allow_upstream: bool) -> (String, String) {
+        let upstream = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap().to_string();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (conn, _) = listener.accept().await.unwrap();
+        let allowed: Arc<[String]> = match allow_upstream {
+            true => Arc::from([upstream_addr.clone()]),
+            false => Arc::from([]),
+        };
+        let forwarded = tokio::spawn(forward(conn, allowed));
+
+        let request = String::from_utf8_lossy(request).replace("UPSTREAM", &upstream_addr);
+        client.write_all(request.as_bytes()).await.unwrap();
+        let received =
+            match tokio::time::timeout(Duration::from_millis(500), upstream.accept()).await {
+                Err(_) => String::new(),
+                Ok(accepted) => {
+                    let (mut upstream_conn, _) = accepted.unwrap();
+                    let mut received = vec![0; 1024];
+                    let n = upstream_conn.read(&mut received).await.unwrap();
+                    upstream_conn.write_all(b"pong").await.unwrap();
+                    String::from_utf8_lossy(&received[..n]).into_owned()
+                }
+            };
+        client.shutdown().await.unwrap();
+        let mut response = String::new();
+        let _ = client.read_to_string(&mut response).await.unwrap();
+        forwarded.abort();
+        (response, received)
+    }
+
+    #[tokio::test]
+    async fn proxy_forwards_only_to_allowed_destinations() {
+        let get = b"GET http://UPSTREAM/ping HTTP/1.1\r\nHost: UPSTREAM\r\n\r\n";
+        let (fetched, sent) = proxy(get, true).await;
+        assert_eq!(fetched, "pong");
+        assert!(sent.starts_with("GET /ping HTTP/1.1\r\nHost: "), "{sent}");
+
+        let (tunneled, tunnel_sent) = proxy(b"CONNECT UPSTREAM HTTP/1.1\r\n\r\nping", true).await;
+        assert_eq!(tunneled, "HTTP/1.1 200 Connection established\r\n\r\npong");
+        assert_eq!(tunnel_sent, "ping");
+
+        let (denied, denied_sent) = proxy(get, false).await;
+        assert!(denied.starts_with("HTTP/1.1 403 "), "{denied}");
+        assert_eq!(denied_sent, "");
+
+        let (origin_form, _) = proxy(b"GET /ping HTTP/1.1\r\n\r\n", true).await;
+        assert!(origin_form.starts_with("HTTP/1.1 400 "), "{origin_form}");
+
+        let (too_long, _) = proxy(&[b'a'; MAX_HEAD], true).await;
+        assert!(too_long.starts_with("HTTP/1.1 431 "), "{too_long}");
+    }
+}
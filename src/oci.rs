@@ -0,0 +1,256 @@
+use log::warn;
+use serde_json::{json, Value};
+use std::process::{Command as StdCommand, Stdio};
+use tokio::process::Command;
+
+use crate::namespace::effective_ids;
+use crate::network::{Policy as NetworkPolicy, PROXY_PORT};
+use crate::seccomp::Profile as SeccompProfile;
+
+/// Linux namespaces of the container, without the network one
+const NAMESPACES: [&str; 5] = ["pid", "ipc", "uts", "mount", "cgroup"];
+
+/// `PATH` of the container processes
+const PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Process and filesystem of a container
+#[derive(Debug)]
+pub struct Spec<'a> {
+    /// Root filesystem directory
+    pub rootfs: &'a str,
+    /// Host directory bound read-only at the same place
+    pub path: &'a str,
+    /// Hostname in the container
+    pub hostname: &'a str,
+    /// Command line of the process
+    pub args: Vec<&'a str>,
+    /// Environment of the process
+    pub envs: &'a [(&'a str, &'a str)],
+    /// cgroup of the container from the root of the hierarchy
+    pub cgroups_path: Option<&'a str>,
+}
+
+/// Generate the `config.json` of the OCI runtime spec
+#[must_use]
+pub fn config(
+    spec: &Spec<'_>,
+    network: Option<&NetworkPolicy>,
+    seccomp: Option<SeccompProfile>,
+) -> Value {
+    let mut env = vec![("PATH", PATH.to_string()), ("TMPDIR", "/tmp".to_string())];
+    // Without a network policy the container gets its own network namespace,
+    // with one it joins the namespace prepared for the runtime
+    let mut namespaces: Vec<Value> = NAMESPACES.iter().map(|t| json!({ "type": t })).collect();
+    match network {
+        None => namespaces.push(json!({ "type": "network" })),
+        Some(NetworkPolicy::Allowlist(_)) => {
+            let url = format!("http://127.0.0.1:{PROXY_PORT}");
+            for var in ["http_proxy", "https_proxy", "HTTP_PROXY", "HTTPS_PROXY"] {
+                env.push((var, url.clone()));
+            }
+        }
+        Some(_) => {}
+    }
+    // The lambda environment overrides the defaults rather than duplicating them
+    env.retain(|(var, _)| spec.envs.iter().all(|(key, _)| key != var));
+    let env: Vec<_> = env
+        .iter()
+        .map(|(k, v)| (*k, v.as_str()))
+        .chain(spec.envs.iter().copied())
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect();
+
+    // Root of the container is the server user, so rootless runtimes accept it
+    namespaces.push(json!({ "type": "user" }));
+    let (uid, gid) = effective_ids();
+
+    let mut linux = json!({
+        "namespaces": namespaces,
+        "uidMappings": [{ "containerID": 0, "hostID": uid, "size": 1 }],
+        "gidMappings": [{ "containerID": 0, "hostID": gid, "size": 1 }],
+        "maskedPaths": ["/proc/kcore", "/proc/keys", "/proc/timer_list", "/sys/firmware"],
+        "readonlyPaths": ["/proc/bus", "/proc/fs", "/proc/irq", "/proc/sys", "/proc/sysrq-trigger"],
+    });
+    if let Some(path) = spec.cgroups_path {
+        linux["cgroupsPath"] = path.into();
+    }
+    if let Some(profile) = seccomp {
+        let mut syscalls = vec![json!({
+            "names": profile.denied_names(),
+            "action": "SCMP_ACT_ERRNO",
+            "errnoRet": libc::EPERM,
+        })];
+        // Rules of a syscall apply when all their argument checks match, one per flag
+        let clone_flags = profile.denied_clone_flags();
+        syscalls.extend(clone_flags.iter().map(|flag| {
+            json!({
+                "names": ["clone"],
+                "action": "SCMP_ACT_ERRNO",
+                "errnoRet": libc::EPERM,
+                "args": [{ "index": 0, "value": flag, "valueTwo": flag, "op": "SCMP_CMP_MASKED_EQ" }],
+            })
+        }));
+        if !clone_flags.is_empty() {
+            syscalls.push(json!({
+                "names": ["clone3"],
+                "action": "SCMP_ACT_ERRNO",
+                "errnoRet": libc::ENOSYS,
+            }));
+        }
+        linux["seccomp"] = json!({ "defaultAction": "SCMP_ACT_ALLOW", "syscalls": syscalls });
+    }
+
+    json!({
+        "ociVersion": "1.0.2",
+        "process": {
+            "terminal": false,
+            "user": { "uid": 0, "gid": 0 },
+            "args": spec.args,
+            "env": env,
+            "cwd": "/tmp",
+            "noNewPrivileges": true,
+            "capabilities": {
+                "bounding": ["CAP_KILL", "CAP_NET_BIND_SERVICE"],
+                "effective": ["CAP_KILL", "CAP_NET_BIND_SERVICE"],
+                "permitted": ["CAP_KILL", "CAP_NET_BIND_SERVICE"],
+            },
+        },
+        "root": { "path": spec.rootfs, "readonly": true },
+        "hostname": spec.hostname,
+        "mounts": [
+            { "destination": "/proc", "type": "proc", "source": "proc" },
+            {
+                "destination": "/dev",
+                "type": "tmpfs",
+                "source": "tmpfs",
+                "options": ["nosuid", "strictatime", "mode=755", "size=65536k"],
+            },
+            // A user namespace can't mount a sysfs of a network namespace it doesn't own
+            {
+                "destination": "/sys",
+                "type": "none",
+                "source": "/sys",
+                "options": ["rbind", "nosuid", "noexec", "nodev", "ro"],
+            },
+            {
+                "destination": "/tmp",
+                "type": "tmpfs",
+                "source": "tmpfs",
+                "options": ["nosuid", "nodev", "mode=1777"],
+            },
+            {
+                "destination": spec.path,
+                "type": "bind",
+                "source": spec.path,
+                "options": ["rbind", "ro"],
+            },
+        ],
+        "linux": linux,
+    })
+}
+
+/// A container run by an OCI runtime, its state is deleted on drop
+#[derive(Debug)]
+pub struct Container {
+    /// Runtime binary
+    pub runtime: String,
+    /// Container ID
+    pub id: String,
+}
+
+impl Container {
+    /// Kill the container processes, they aren't in the process group of the runtime
+    pub async fn kill(&self) {
+        let killed = Command::new(&self.runtime)
+            .args(["kill", &self.id, "KILL"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        if let Err(e) = killed {
+            warn!("Failed to kill container {}: {e}", self.id);
+        }
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        // Already gone when the runtime saw its process exit
+        let mut delete = StdCommand::new(&self.runtime);
+        let _ = delete
+            .args(["delete", "--force", &self.id])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        let id = std::mem::take(&mut self.id);
+        match tokio::runtime::Handle::try_current() {
+            // Waited for in the background, not to stall a runtime worker
+            Ok(runtime) => std::mem::drop(runtime.spawn(async move {
+                if let Err(e) = Command::from(delete).status().await {
+                    warn!("Failed to delete container {id}: {e}");
+                }
+            })),
+            Err(_) => {
+                if let Err(e) = delete.status() {
+                    warn!("Failed to delete container {id}: {e}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec<'a>(envs: &'a [(&'a str, &'a str)]) -> Spec<'a> {
+        Spec {
+            rootfs: "/srv/rootfs",
+            path: "/srv/lambdas",
+            hostname: "RESTRICTED",
+            args: vec!["/srv/lambdas/hello.sh", "hi"],
+            envs,
+            cgroups_path: Some("/freeitw/leaf-1"),
+        }
+    }
+
+    #[test]
+    fn config_runs_the_lambda_read_only_as_the_server_user() {
+        let config = config(&spec(&[("PATH", "/bin"), ("A", "1")]), None, None);
+        let (uid, gid) = effective_ids();
+        assert_eq!(config["process"]["args"], json!(["/srv/lambdas/hello.sh", "hi"]));
+        assert_eq!(config["process"]["env"], json!(["TMPDIR=/tmp", "PATH=/bin", "A=1"]));
+        assert_eq!(config["root"], json!({ "path": "/srv/rootfs", "readonly": true }));
+        assert_eq!(config["hostname"], "RESTRICTED");
+        let lambdas = config["mounts"].as_array().unwrap().last().unwrap();
+        assert_eq!(lambdas["source"], "/srv/lambdas");
+        assert_eq!(lambdas["options"], json!(["rbind", "ro"]));
+        let linux = &config["linux"];
+        assert_eq!(linux["uidMappings"][0], json!({ "containerID": 0, "hostID": uid, "size": 1 }));
+        assert_eq!(linux["gidMappings"][0], json!({ "containerID": 0, "hostID": gid, "size": 1 }));
+        assert_eq!(linux["cgroupsPath"], "/freeitw/leaf-1");
+        let namespaces: Vec<_> =
+            linux["namespaces"].as_array().unwrap().iter().map(|n| &n["type"]).collect();
+        assert_eq!(namespaces, ["pid", "ipc", "uts", "mount", "cgroup", "network", "user"]);
+        assert!(linux.get("seccomp").is_none());
+    }
+
+    #[test]
+    fn config_follows_the_network_and_seccomp_options() {
+        let allowlist = NetworkPolicy::Allowlist(vec!["example.com:443".to_string()]);
+        let config =
+            config(&spec(&[]), Some(&allowlist), Some(SeccompProfile::DefaultDenyDangerous));
+        // The runtime network namespace is joined
+        let namespaces = config["linux"]["namespaces"].as_array().unwrap();
+        assert!(namespaces.iter().all(|n| n["type"] != "network"));
+        let env = config["process"]["env"].as_array().unwrap();
+        assert!(env.contains(&json!("https_proxy=http://127.0.0.1:3128")));
+
+        let syscalls = config["linux"]["seccomp"]["syscalls"].as_array().unwrap();
+        assert!(syscalls[0]["names"].as_array().unwrap().contains(&json!("unshare")));
+        assert_eq!(syscalls[0]["errnoRet"], libc::EPERM);
+        let clone_rules = syscalls.iter().filter(|s| s["names"] == json!(["clone"])).count();
+        assert_eq!(clone_rules, 7);
+        let clone3 = syscalls.iter().find(|s| s["names"] == json!(["clone3"])).unwrap();
+        assert_eq!(clone3["errnoRet"], libc::ENOSYS);
+    }
+}
@@ -0,0 +1,138 @@
+use serde::Serialize;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+
+/// How a process ended and the resources it used
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Exit {
+    /// Exit code when it exited
+    pub code: Option<i32>,
+    /// Signal number when it was killed
+    pub signal: Option<i32>,
+    /// Wall clock time since the spawn, in milliseconds
+    pub duration_ms: u128,
+    /// CPU time in user mode, in milliseconds
+    pub user_ms: u128,
+    /// CPU time in kernel mode, in milliseconds
+    pub system_ms: u128,
+    /// Peak resident set size, in KiB
+    pub max_rss_kb: i64,
+}
+
+/// Convert a `timeval` to a `Duration`
+fn duration(tv: libc::timeval) -> Duration {
+    Duration::from_secs(u64::try_from(tv.tv_sec).unwrap_or_default())
+        + Duration::from_micros(u64::try_from(tv.tv_usec).unwrap_or_default())
+}
+
+/// Wait for the child `pid` to end, with its resource usage including the descendants
+/// it waited for. It is left to be reaped by its owner, until then its zombie keeps
+/// its pid and process group from being reused.
+/// The pidfd is polled by the runtime, no thread is held while waiting
+/// # Errors
+///     when `pid` isn't an unreaped child of this process
+pub async fn wait_exit(pid: u32, started: Instant) -> io::Result<Exit> {
+    let pidfd = AsyncFd::with_interest(pidfd_open(pid)?, Interest::READABLE)?;
+    loop {
+        let mut guard = pidfd.readable().await?;
+        match peek_exit(guard.get_inner(), started)? {
+            Some(exit) => return Ok(exit),
+            None => guard.clear_ready(),
+        }
+    }
+}
+
+/// Open a pidfd of the process `pid`, readable once it ended
+fn pidfd_open(pid: u32) -> io::Result<OwnedFd> {
+    let pid = libc::pid_t::try_from(pid).map_err(io::Error::other)?;
+    // SAFETY: pidfd_open only takes integers
+    #[allow(unsafe_code, reason = "no safe wrapper for pidfd_open in std")]
+    let ret = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = RawFd::try_from(ret).map_err(io::Error::other)?;
+    // SAFETY: the fd was just opened and nothing else owns it
+    #[allow(unsafe_code, reason = "taking ownership of a raw fd")]
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Exit status and resource usage of the process of `pidfd`, `None` while it runs.
+/// It is not reaped
+fn peek_exit(pidfd: &OwnedFd, started: Instant) -> io::Result<Option<Exit>> {
+    // SAFETY: siginfo_t and rusage are plain old data, zero is a valid value
+    #[allow(unsafe_code, reason = "no safe wrapper for waitid in std")]
+    let (mut info, mut usage): (libc::siginfo_t, libc::rusage) =
+        unsafe { (std::mem::zeroed(), std::mem::zeroed()) };
+    let options = libc::WEXITED | libc::WNOWAIT | libc::WNOHANG;
+    loop {
+        // The raw syscall, the libc wrapper has no rusage argument
+        // SAFETY: info and usage are valid for writes during the call
+        #[allow(unsafe_code, reason = "no safe wrapper for waitid in std")]
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_waitid,
+                libc::P_PIDFD,
+                pidfd.as_raw_fd(),
+                &raw mut info,
+                options,
+                &raw mut usage,
+            )
+        };
+        if ret >= 0 {
+            break;
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+    // SAFETY: the fields of a child event, zero when none is pending
+    #[allow(unsafe_code, reason = "siginfo_t is a union")]
+    let (pid, status) = unsafe { (info.si_pid(), info.si_status()) };
+    if pid == 0 {
+        return Ok(None);
+    }
+    let killed = matches!(info.si_code, libc::CLD_KILLED | libc::CLD_DUMPED);
+    Ok(Some(Exit {
+        code: (info.si_code == libc::CLD_EXITED).then_some(status),
+        signal: killed.then_some(status),
+        duration_ms: started.elapsed().as_millis(),
+        user_ms: duration(usage.ru_utime).as_millis(),
+        system_ms: duration(usage.ru_stime).as_millis(),
+        max_rss_kb: usage.ru_maxrss,
+    }))
+}
+
+/// Kill the process group led by `leader`, that is a lambda process and its descendants.
+/// The leader must not be reaped yet, or the group id may already be reused
+/// # Errors
+///     when the group doesn't exist anymore
+pub fn kill_group(leader: u32) -> io::Result<()> {
+    let group = libc::pid_t::try_from(leader).map_err(io::Error::other)?;
+    // SAFETY: kill only takes integers and has no memory safety requirement
+    #[allow(unsafe_code, reason = "no safe wrapper for killpg in std")]
+    let ret = unsafe { libc::kill(-group, libc::SIGKILL) };
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Keep `fd` open in the spawned program, std opens every fd with `O_CLOEXEC`
+pub fn inherit_on_exec(cmd: &mut std::process::Command, fd: RawFd) {
+    // SAFETY: the closure only calls fcntl(2) which is async-signal-safe,
+    // the caller keeps `fd` open until the spawn
+    #[allow(unsafe_code, reason = "the flag must be cleared between fork and exec")]
+    let _ = unsafe {
+        std::os::unix::process::CommandExt::pre_exec(cmd, move || {
+            match libc::fcntl(fd, libc::F_SETFD, 0) {
+                0 => Ok(()),
+                _ => Err(io::Error::last_os_error()),
+            }
+        })
+    };
+}
@@ -0,0 +1,101 @@
+# Entrypoint runner for python lambdas
+#
+# Load the lambda module, decode the request body as JSON kwargs,
+# or as a pandas DataFrame passed first when FREEITW_INPUT_FORMAT is set,
+# call the entrypoint and write its serialized return value on stdout.
+# pandas DataFrame/Series are serialized in FREEITW_OUTPUT_FORMAT,
+# other values as JSON. Uncaught exceptions are reported as a JSON error object and exit status 1.
+# The output starts with a `FREEITW-Content-Type: <type>` line telling the server what was produced.
+import contextlib
+import io
+import json
+import os
+import sys
+import traceback
+
+
+def freeitw_read_input(fmt):
+    import pandas as pd
+
+    if fmt == "csv":
+        return pd.read_csv(sys.stdin.buffer)
+    if fmt == "csv-noheader":
+        return pd.read_csv(sys.stdin.buffer, header=None)
+    if fmt == "tsv":
+        return pd.read_csv(sys.stdin.buffer, sep="\t")
+    if fmt == "json":
+        return pd.read_json(io.StringIO(sys.stdin.read()), orient="records")
+    if fmt == "ndjson":
+        return pd.read_json(io.StringIO(sys.stdin.read()), orient="records", lines=True)
+    if fmt == "parquet":
+        return pd.read_parquet(io.BytesIO(sys.stdin.buffer.read()))
+    raise ValueError(f"unsupported input format '{fmt}'")
+
+
+def freeitw_serialize(result, fmt):
+    pd = sys.modules.get("pandas")
+    if pd is None or not isinstance(result, (pd.DataFrame, pd.Series)):
+        return json.dumps(result).encode(), "application/json"
+
+    if fmt == "split":
+        return result.to_json(orient="split").encode(), "application/json"
+    if fmt == "csv":
+        index = not isinstance(result.index, pd.RangeIndex)
+        return result.to_csv(index=index).encode(), "text/csv"
+
+    df = result.to_frame() if isinstance(result, pd.Series) else result
+    if fmt == "parquet":
+        buf = io.BytesIO()
+        df.to_parquet(buf)
+        return buf.getvalue(), "application/vnd.apache.parquet"
+    if fmt == "arrow":
+        import pyarrow as pa
+
+        table = pa.Table.from_pandas(df)
+        sink = pa.BufferOutputStream()
+        with pa.ipc.new_stream(sink, table.schema) as writer:
+            writer.write_table(table)
+        return sink.getvalue().to_pybytes(), "application/vnd.apache.arrow.stream"
+    return result.to_json(orient="records").encode(), "application/json"
+
+
+def freeitw_write(out, content_type):
+    sys.stdout.buffer.write(f"FREEITW-Content-Type: {content_type}\n".encode())
+    sys.stdout.buffer.write(out)
+    sys.stdout.buffer.flush()
+
+
+def freeitw_run(module_path, entrypoint):
+    try:
+        # Anything the lambda prints goes to stderr so stdout only holds the result
+        with contextlib.redirect_stdout(sys.stderr):
+            namespace = {"__name__": "__lambda__", "__file__": module_path}
+            with open(module_path, encoding="utf-8") as f:
+                exec(compile(f.read(), module_path, "exec"), namespace)
+            func = namespace.get(entrypoint)
+            if not callable(func):
+                raise NameError(f"entrypoint '{entrypoint}' is not a function")
+
+            input_format = os.environ.get("FREEITW_INPUT_FORMAT")
+            if input_format:
+                result = func(freeitw_read_input(input_format))
+            else:
+                raw = sys.stdin.read()
+                kwargs = json.loads(raw) if raw.strip() else {}
+                if not isinstance(kwargs, dict):
+                    raise TypeError("request body must be a JSON object")
+                result = func(**kwargs)
+        out, content_type = freeitw_serialize(
+            result, os.environ.get("FREEITW_OUTPUT_FORMAT", "records")
+        )
+    except Exception as e:
+        error = {
+            "error": {
+                "type": type(e).__name__,
+                "message": str(e),
+                "traceback": traceback.format_exc(),
+            }
+        }
+        freeitw_write(json.dumps(error).encode(), "application/json")
+        sys.exit(1)
+    freeitw_write(out, content_type)
@@ -0,0 +1,48 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::process::Command;
+
+/// POSIX resource limits set in the child before exec, unset limits are inherited
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct Rlimits {
+    /// `RLIMIT_AS`, address space in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address_space: Option<u64>,
+    /// `RLIMIT_CPU`, cpu time in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u64>,
+    /// `RLIMIT_NOFILE`, number of open files
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nofile: Option<u64>,
+    /// `RLIMIT_FSIZE`, size of written files in bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fsize: Option<u64>,
+    /// `RLIMIT_NPROC`, number of processes of the user, not only the invocation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nproc: Option<u64>,
+}
+
+/// Set the limits in the spawned process before exec
+pub fn apply_on_exec(cmd: &mut Command, limits: Rlimits) {
+    // SAFETY: the closure only calls setrlimit(2) which is async-signal-safe and doesn't allocate
+    #[allow(unsafe_code, reason = "limits must be set between fork and exec")]
+    let _ = unsafe {
+        cmd.pre_exec(move || {
+            let set = |resource, value: Option<u64>| match value {
+                None => Ok(()),
+                Some(value) => {
+                    let limit = libc::rlimit { rlim_cur: value, rlim_max: value };
+                    match libc::setrlimit(resource, &raw const limit) {
+                        0 => Ok(()),
+                        _ => Err(io::Error::last_os_error()),
+                    }
+                }
+            };
+            set(libc::RLIMIT_AS, limits.address_space)?;
+            set(libc::RLIMIT_CPU, limits.cpu)?;
+            set(libc::RLIMIT_NOFILE, limits.nofile)?;
+            set(libc::RLIMIT_FSIZE, limits.fsize)?;
+            set(libc::RLIMIT_NPROC, limits.nproc)
+        })
+    };
+}
@@ -1,60 +1,330 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use log::warn;
 use std::fs;
-use std::io::prelude::*;
-use std::os::unix::fs::PermissionsExt;
+use std::io::{prelude::*, PipeReader};
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Component, Path};
+use std::process::Command as StdCommand;
+use std::sync::atomic::{AtomicU64, Ordering};
 use tokio::process::Command;
 
 use enum_dispatch::enum_dispatch;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-// TODO add docker
+use crate::cgroup::{Leaf as CgroupLeaf, Limits as CgroupLimits, CGROUP_ROOT};
+use crate::landlock::{restrict_on_exec, Rules as LandlockRules};
+use crate::namespace::{enter_on_exec as enter_namespace_on_exec, Plan as NamespacePlan};
+use crate::network::{isolate_on_exec, Policy as NetworkPolicy, Proxy};
+use crate::oci::{config as oci_config, Container, Spec as OciSpec};
+use crate::process::inherit_on_exec;
+use crate::rlimit::{apply_on_exec as apply_rlimits, Rlimits};
+use crate::seccomp::{apply_on_exec as apply_seccomp, Profile as SeccompProfile};
+
+/// Counter naming OCI containers
+static CONTAINER_ID: AtomicU64 = AtomicU64::new(0);
 
 /// Kind of sandbox to isolate code
-#[allow(clippy::module_name_repetitions)]
-#[derive(Serialize, Debug)]
+#[allow(clippy::module_name_repetitions, reason = "Kind suffix names the dispatch enum")]
+#[derive(Serialize, Deserialize, Debug)]
 #[enum_dispatch]
 pub enum SandboxKind {
     /// Host wrapper
     Host(Host),
     /// Bwrap wrapper
     BubbleWrap(BubbleWrap),
+    /// Bwrap wrapper on a copy-on-write rootfs
+    Overlay(Overlay),
+    /// Native namespaces
+    Namespace(Namespace),
+    /// OCI runtime container
+    Oci(Oci),
+}
+
+/// A sandbox with its hardening options
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Sandbox {
+    /// The isolation
+    #[serde(flatten)]
+    pub kind: SandboxKind,
+    /// Options common to every kind
+    #[serde(flatten)]
+    pub options: Options,
+}
+
+/// Hardening options common to every kind of sandbox
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Options {
+    /// cgroup v2 limits of each invocation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cgroup: Option<CgroupLimits>,
+    /// POSIX resource limits of each invocation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rlimits: Option<Rlimits>,
+    /// Syscall filter of each invocation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seccomp: Option<SeccompProfile>,
+    /// Landlock filesystem rules of each invocation, `Host` sandbox only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub landlock: Option<LandlockRules>,
+    /// Network access of each invocation, the kind's own setup when unset
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkPolicy>,
+}
+
+impl From<SandboxKind> for Sandbox {
+    fn from(kind: SandboxKind) -> Self {
+        Self { kind, options: Options::default() }
+    }
+}
+
+impl Trait for Sandbox {
+    fn prepare_spawn(
+        &self,
+        prg: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+    ) -> Result<(Command, Scope)> {
+        let (mut cmd, mut scope) = self.kind.prepare_spawn(prg, args, envs, &self.options)?;
+        // Unless the kind already placed the lambda in its leaf
+        if let (Some(limits), None) = (&self.options.cgroup, &scope.cgroup) {
+            scope.cgroup = Some(limits.enter(&mut cmd)?);
+        }
+        if let Some(limits) = self.options.rlimits {
+            apply_rlimits(&mut cmd, limits);
+        }
+        Ok((cmd, scope))
+    }
+
+    fn injest(&self, content: &[u8], filename: &str) -> Result<()> {
+        self.kind.injest(content, filename)
+    }
+}
+
+impl Sandbox {
+    /// Check the host paths of a sandbox given by a client, the directories it writes in
+    /// or mounts must be under `root` and its cgroup parent in the cgroup hierarchy
+    /// # Errors
+    ///     naming the first path out of bounds
+    pub fn check_paths(&self, root: &Path) -> Result<()> {
+        let cgroup =
+            self.options.cgroup.as_ref().map(|c| (c.parent.as_str(), Path::new(CGROUP_ROOT)));
+        let paths = self.kind.host_paths().into_iter().map(|p| (p, root)).chain(cgroup);
+        for (path, bound) in paths {
+            if !is_beneath(path, bound) {
+                return Err(anyhow!("{path} is not under {}", bound.display()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `path` is absolute and stays under `root` once the symlinks
+/// of its existing part are resolved
+fn is_beneath(path: &str, root: &Path) -> bool {
+    let path = Path::new(path);
+    if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+        return false;
+    }
+    // The part which doesn't exist yet can't hold a symlink
+    let (mut existing, mut missing) = (path, Vec::new());
+    let resolved = loop {
+        if let Ok(resolved) = existing.canonicalize() {
+            break resolved;
+        }
+        let (Some(name), Some(parent)) = (existing.file_name(), existing.parent()) else {
+            return false;
+        };
+        missing.push(name);
+        existing = parent;
+    };
+    let resolved = missing.iter().rev().fold(resolved, |p, name| p.join(name));
+    resolved.starts_with(root.canonicalize().unwrap_or_else(|_| root.to_path_buf()))
+}
+
+/// Resources the sandbox holds for a single invocation, released on drop
+#[derive(Debug, Default)]
+pub struct Scope {
+    cgroup: Option<CgroupLeaf>,
+    /// Read end of the pipe handing the seccomp program to bwrap
+    seccomp: Option<PipeReader>,
+    /// Landlock ruleset, open until the spawn
+    landlock: Option<OwnedFd>,
+    /// Container of an OCI runtime, dropped before its bundle
+    container: Option<Container>,
+    /// Private working directory of the invocation
+    scratch: Option<Scratch>,
+    /// Egress proxy of an allowlisted network
+    proxy: Option<Proxy>,
+}
+
+impl Scope {
+    /// Kill the processes of the invocation the process group of the lambda doesn't hold
+    pub async fn kill(&self) {
+        if let Some(container) = &self.container {
+            container.kill().await;
+        }
+    }
+
+    /// Whether the invocation was killed for running out of memory
+    #[must_use]
+    pub fn oom_killed(&self) -> bool {
+        self.cgroup.as_ref().is_some_and(CgroupLeaf::oom_killed)
+    }
+}
+
+/// Per invocation private directory, removed with its content on drop
+#[derive(Debug)]
+pub struct Scratch {
+    /// Directory path
+    pub path: String,
+}
+
+impl Scratch {
+    /// Create a fresh directory only the server user can access in the system temporary
+    /// directory. Its name is random and it must not exist, so another user can't
+    /// prepare it, nor plant a symlink where it is created
+    /// # Errors
+    ///     IO errors
+    pub fn new() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!("freeitw_scratch-{}", Uuid::new_v4()));
+        fs::DirBuilder::new().mode(0o700).create(&path)?;
+        Ok(Self { path: path.to_string_lossy().into_owned() })
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let path = Path::new(&self.path);
+        if let Err(e) = make_removable(path).and_then(|()| fs::remove_dir_all(path)) {
+            warn!("Failed to remove scratch directory {}: {e}", self.path);
+        }
+    }
+}
+
+/// Give the owner full access to the directories under `path` so their content
+/// can be removed, overlayfs leaves its `work/work` directory with mode 000
+fn make_removable(path: &Path) -> std::io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(());
+    }
+    let mode = metadata.permissions().mode();
+    if mode & 0o700 != 0o700 {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o700))?;
+    }
+    for entry in fs::read_dir(path)? {
+        make_removable(&entry?.path())?;
+    }
+    Ok(())
+}
+
+/// Write `content` to the executable file `filename` of the lambdas directory `dir`
+fn write_executable(dir: &str, content: &[u8], filename: &str) -> Result<()> {
+    let path = format!("{dir}/{filename}");
+    let mut file = fs::File::create(&path)?;
+    file.write_all(content)?;
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+    Ok(())
 }
 
 /// Trait to implement sandboxes
-#[enum_dispatch(SandboxKind)]
 pub trait Trait {
-    /// Spawn in the sandbox
+    /// Spawn `prg` with `args` and `envs` in the sandbox, the process leads
+    /// its own process group so it can be killed with its descendants
+    /// # Errors
+    ///     Command errors
+    fn prepare_spawn(
+        &self,
+        prg: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+    ) -> Result<(Command, Scope)>;
+    /// Copy resource in the sandbox
+    /// # Errors
+    ///     IO errors
+    fn injest(&self, content: &[u8], filename: &str) -> Result<()>;
+}
+
+/// Trait to implement the isolation of a sandbox kind, the `Sandbox` adds the options
+/// which don't depend on it
+#[enum_dispatch(SandboxKind)]
+pub trait Isolation {
+    /// Spawn `prg` with `args` and `envs` in the sandbox, the process leads
+    /// its own process group so it can be killed with its descendants.
+    /// Kinds apply the `options` which depend on how they isolate
     /// # Errors
     ///     Command errors
-    fn prepare_spawn(&self, prg: &str) -> Command;
+    fn prepare_spawn(
+        &self,
+        prg: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+        options: &Options,
+    ) -> Result<(Command, Scope)>;
     /// Copy resource in the sandbox
     /// # Errors
     ///     IO errors
     fn injest(&self, content: &[u8], filename: &str) -> Result<()>;
+    /// Host paths the sandbox writes in or mounts
+    fn host_paths(&self) -> Vec<&str>;
 }
 
 /// A no sandbox sandbox
-#[derive(Serialize, Debug)]
+///
+/// The invocation works in a scratch directory exported as `TMPDIR`,
+/// `/tmp` itself is still the shared host one.
+#[derive(Serialize, Deserialize, Debug)]
 pub struct Host(pub String);
 
-impl Trait for Host {
-    fn prepare_spawn(&self, prg: &str) -> Command {
-        let mut cmd = Command::new(self.0.clone() + "/" + prg);
-        let _ = cmd.current_dir(&self.0);
-        cmd
+impl Isolation for Host {
+    fn prepare_spawn(
+        &self,
+        prg: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+        options: &Options,
+    ) -> Result<(Command, Scope)> {
+        // The host can't mount, the invocation works in a private directory instead
+        let scratch = Scratch::new()?;
+        let mut cmd = StdCommand::new(self.0.clone() + "/" + prg);
+        let _ = cmd
+            .args(args)
+            .current_dir(&scratch.path)
+            .env("TMPDIR", &scratch.path)
+            .envs(envs.iter().copied())
+            .process_group(0);
+        let mut cmd = Command::from(cmd);
+        let mut scope = Scope::default();
+        // Before the syscall filter, which denies unshare
+        if let Some(policy) = &options.network {
+            scope.proxy = isolate_on_exec(&mut cmd, policy, false)?;
+        }
+        if let Some(profile) = options.seccomp {
+            apply_seccomp(&mut cmd, profile)?;
+        }
+        if let Some(rules) = &options.landlock {
+            let ruleset = rules.ruleset(&self.0, &scratch.path)?;
+            restrict_on_exec(&mut cmd, &ruleset);
+            scope.landlock = Some(ruleset);
+        }
+        scope.scratch = Some(scratch);
+        Ok((cmd, scope))
     }
 
     fn injest(&self, content: &[u8], filename: &str) -> Result<()> {
-        let path = self.0.clone() + "/" + filename;
-        let mut file = fs::File::create(&path)?;
-        file.write_all(content)?;
-        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
-        Ok(())
+        write_executable(&self.0, content, filename)
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        vec![self.0.as_str()]
     }
 }
 
 /// Bwrap sandbox
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct BubbleWrap {
     path: String,
     options: Vec<String>,
@@ -69,28 +339,317 @@ impl BubbleWrap {
     }
 }
 
-impl Trait for BubbleWrap {
-    fn prepare_spawn(&self, prg: &str) -> Command {
-        let mut cmd = Command::new("/usr/bin/bwrap");
-        let _ = cmd
-            .args(["--bind", self.path.as_str(), self.path.as_str()])
-            .args(&self.options)
-            .args(["--"])
-            .args([self.path.to_string() + "/" + prg]);
-        cmd
+impl Isolation for BubbleWrap {
+    fn prepare_spawn(
+        &self,
+        prg: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+        options: &Options,
+    ) -> Result<(Command, Scope)> {
+        // bwrap mounts in order, the private scratch must not hide the lambdas
+        // directory when it lives under `/tmp`
+        let mounts = [
+            "--tmpfs",
+            "/tmp",
+            "--chdir",
+            "/tmp",
+            "--ro-bind",
+            self.path.as_str(),
+            self.path.as_str(),
+        ];
+        let program = self.path.clone() + "/" + prg;
+        bwrap_command(&mounts, &self.options, &program, args, envs, options, Scope::default())
     }
 
     fn injest(&self, content: &[u8], filename: &str) -> Result<()> {
-        let path = self.path.clone() + "/" + filename;
-        let mut file = fs::File::create(&path)?;
-        file.write_all(content)?;
-        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
-        Ok(())
+        write_executable(&self.path, content, filename)
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        vec![self.path.as_str()]
+    }
+}
+
+/// Build the bwrap command running `program` in the `mounts` and the `extra` arguments,
+/// bwrap passes the environment through. The lambda always dies with bwrap, so killing
+/// its process group also reaches a lambda the `extra` arguments put in a new session
+fn bwrap_command(
+    mounts: &[&str],
+    extra: &[String],
+    program: &str,
+    args: &[&str],
+    envs: &[(&str, &str)],
+    options: &Options,
+    mut scope: Scope,
+) -> Result<(Command, Scope)> {
+    // A Landlock restricted process can't set up mounts
+    if options.landlock.is_some() {
+        return Err(anyhow!("Landlock is not supported by bwrap sandboxes"));
+    }
+    let mut cmd = StdCommand::new("/usr/bin/bwrap");
+    let _ = cmd
+        .args(mounts)
+        .args(extra)
+        .arg("--die-with-parent")
+        .envs(envs.iter().copied())
+        .process_group(0);
+    // bwrap keeps the network namespace prepared before exec,
+    // whether or not the profile already shares the network
+    if options.network.is_some() {
+        let _ = cmd.arg("--share-net");
+    }
+    // bwrap installs the filter itself, reading it from an inherited fd
+    if let Some(profile) = options.seccomp {
+        let (reader, mut writer) = std::io::pipe()?;
+        writer.write_all(&profile.to_bytes()?)?;
+        std::mem::drop(writer);
+        let fd = reader.as_raw_fd();
+        let _ = cmd.args(["--seccomp", &fd.to_string()]);
+        inherit_on_exec(&mut cmd, fd);
+        scope.seccomp = Some(reader);
+    }
+    let _ = cmd.args(["--", program]).args(args);
+    let mut cmd = Command::from(cmd);
+    if let Some(policy) = &options.network {
+        scope.proxy = isolate_on_exec(&mut cmd, policy, false)?;
+    }
+    Ok((cmd, scope))
+}
+
+/// Bwrap sandbox with a copy-on-write root
+///
+/// The root is an overlay of a read-only rootfs image directory and an upper layer
+/// private to the invocation, so a lambda can write anywhere, `pip install` included,
+/// and its changes are dropped when it ends.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Overlay {
+    /// Directory of the lambdas, bound read-only
+    path: String,
+    /// Root filesystem image, the lower layer
+    rootfs: String,
+    /// Extra bwrap arguments, the host network and a fixed hostname by default
+    #[serde(default = "overlay_options")]
+    options: Vec<String>,
+}
+
+/// Default extra bwrap arguments of an `Overlay`, like the default bwrap profile
+fn overlay_options() -> Vec<String> {
+    ["--share-net", "--hostname", "RESTRICTED"].into_iter().map(String::from).collect()
+}
+
+impl Overlay {
+    /// Create a new `Overlay` on the `rootfs` image with the default options
+    #[must_use]
+    pub fn new(path: String, rootfs: String) -> Self {
+        Self { path, rootfs, options: overlay_options() }
+    }
+}
+
+impl Isolation for Overlay {
+    fn prepare_spawn(
+        &self,
+        prg: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+        options: &Options,
+    ) -> Result<(Command, Scope)> {
+        // Upper and work dirs of an overlay must be on the same filesystem
+        let scratch = Scratch::new()?;
+        let upper = scratch.path.clone() + "/upper";
+        let work = scratch.path.clone() + "/work";
+        fs::create_dir_all(&upper)?;
+        fs::create_dir_all(&work)?;
+        // The namespaces are always unshared, the options only add to them.
+        // `/tmp` is private like in the default bwrap profile, the image one is hidden
+        let mounts = [
+            "--unshare-all",
+            "--new-session",
+            "--overlay-src",
+            self.rootfs.as_str(),
+            "--overlay",
+            upper.as_str(),
+            work.as_str(),
+            "/",
+            "--dev",
+            "/dev",
+            "--proc",
+            "/proc",
+            "--tmpfs",
+            "/tmp",
+            "--chdir",
+            "/tmp",
+            "--ro-bind",
+            self.path.as_str(),
+            self.path.as_str(),
+        ];
+        let program = self.path.clone() + "/" + prg;
+        let scope = Scope { scratch: Some(scratch), ..Scope::default() };
+        bwrap_command(&mounts, &self.options, &program, args, envs, options, scope)
+    }
+
+    fn injest(&self, content: &[u8], filename: &str) -> Result<()> {
+        write_executable(&self.path, content, filename)
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        vec![self.path.as_str(), self.rootfs.as_str()]
+    }
+}
+
+/// Namespace sandbox set up natively, without bwrap
+///
+/// The lambda runs as init of new user, mount, pid, ipc, uts and network namespaces,
+/// in a tmpfs root holding read-only binds of the host, `/dev`, `/proc`
+/// and a private `/tmp` it works in.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Namespace {
+    /// Directory of the lambdas, bound read-only
+    path: String,
+    /// Host directory the new root is mounted on
+    root: String,
+    /// Host paths bound read-only at the same place, missing ones are skipped
+    ro_binds: Vec<String>,
+    /// Hostname in the sandbox
+    hostname: String,
+}
+
+impl Isolation for Namespace {
+    fn prepare_spawn(
+        &self,
+        prg: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+        options: &Options,
+    ) -> Result<(Command, Scope)> {
+        // Its tmpfs root is already out of reach of the host files
+        if options.landlock.is_some() {
+            return Err(anyhow!("Landlock is not supported by the Namespace sandbox"));
+        }
+        fs::create_dir_all(&self.root)?;
+        let plan = NamespacePlan::new(&self.root, &self.path, &self.ro_binds, &self.hostname)?;
+
+        let mut cmd = StdCommand::new(self.path.clone() + "/" + prg);
+        let _ = cmd.args(args).envs(envs.iter().copied()).process_group(0);
+        let mut cmd = Command::from(cmd);
+        enter_namespace_on_exec(&mut cmd, plan);
+        let mut scope = Scope::default();
+        // Already root of its user namespace, it can unshare the network again
+        if let Some(policy) = &options.network {
+            scope.proxy = isolate_on_exec(&mut cmd, policy, true)?;
+        }
+        // Installed after the namespace setup, which needs the filtered syscalls
+        if let Some(profile) = options.seccomp {
+            apply_seccomp(&mut cmd, profile)?;
+        }
+        Ok((cmd, scope))
+    }
+
+    fn injest(&self, content: &[u8], filename: &str) -> Result<()> {
+        write_executable(&self.path, content, filename)
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        vec![self.path.as_str(), self.root.as_str()]
+    }
+}
+
+/// Container sandbox run by an OCI runtime, without daemon nor registry
+///
+/// Each invocation gets a bundle with a generated `config.json`
+/// on the read-only `rootfs`, the lambdas directory is bound read-only.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Oci {
+    /// Directory of the lambdas, bound read-only
+    path: String,
+    /// Root filesystem of the container
+    rootfs: String,
+    /// Runtime binary, runc or crun
+    #[serde(default = "default_runtime")]
+    runtime: String,
+    /// Hostname in the container
+    #[serde(default = "default_hostname")]
+    hostname: String,
+}
+
+/// runc in the `PATH`
+fn default_runtime() -> String {
+    "runc".to_string()
+}
+
+/// Hostname of the default sandboxes
+fn default_hostname() -> String {
+    "RESTRICTED".to_string()
+}
+
+impl Isolation for Oci {
+    fn prepare_spawn(
+        &self,
+        prg: &str,
+        args: &[&str],
+        envs: &[(&str, &str)],
+        options: &Options,
+    ) -> Result<(Command, Scope)> {
+        // The runtime sets up mounts, then installs the filter from the config
+        if options.landlock.is_some() {
+            return Err(anyhow!("Landlock is not supported by the Oci sandbox"));
+        }
+        let bundle = Scratch::new()?;
+        let program = self.path.clone() + "/" + prg;
+        // The runtime moves the container in the leaf, not itself
+        let leaf = options.cgroup.as_ref().map(CgroupLimits::create).transpose()?;
+        let cgroups_path = match &leaf {
+            Some(leaf) => Some(leaf.cgroups_path().ok_or_else(|| {
+                anyhow!("The cgroup parent of an Oci sandbox must be under /sys/fs/cgroup")
+            })?),
+            None => None,
+        };
+        let spec = OciSpec {
+            rootfs: &self.rootfs,
+            path: &self.path,
+            hostname: &self.hostname,
+            args: [program.as_str()].into_iter().chain(args.iter().copied()).collect(),
+            envs,
+            cgroups_path,
+        };
+        let config = oci_config(&spec, options.network.as_ref(), options.seccomp);
+        fs::write(bundle.path.clone() + "/config.json", serde_json::to_vec(&config)?)?;
+
+        let id = format!(
+            "freeitw-{}-{}",
+            std::process::id(),
+            CONTAINER_ID.fetch_add(1, Ordering::Relaxed)
+        );
+        let mut cmd = StdCommand::new(&self.runtime);
+        let _ = cmd.args(["run", "--bundle", &bundle.path, &id]).process_group(0);
+        let mut cmd = Command::from(cmd);
+        let mut scope = Scope::default();
+        // The container joins the network namespace of the runtime
+        if let Some(policy) = &options.network {
+            scope.proxy = isolate_on_exec(&mut cmd, policy, false)?;
+        }
+        scope.container = Some(Container { runtime: self.runtime.clone(), id });
+        scope.cgroup = leaf;
+        scope.scratch = Some(bundle);
+        Ok((cmd, scope))
+    }
+
+    fn injest(&self, content: &[u8], filename: &str) -> Result<()> {
+        write_executable(&self.path, content, filename)
+    }
+
+    fn host_paths(&self) -> Vec<&str> {
+        vec![self.path.as_str(), self.rootfs.as_str()]
     }
 }
 
-// Return default sandboxes
-pub fn default_sandboxs(host_wd: String, bwrap_wd: String) -> (Host, BubbleWrap) {
+// Return default sandboxes, an `Overlay` one when given a rootfs image
+pub fn default_sandboxs(
+    wd: &str,
+    rootfs: Option<&str>,
+) -> (Host, BubbleWrap, Namespace, Option<Overlay>) {
+    let host_wd = wd.to_string();
+    let bwrap_wd = wd.to_string();
     let host_sb = Host(host_wd);
     let bwrap_sb = BubbleWrap::new(
         bwrap_wd,
@@ -140,5 +699,124 @@ pub fn default_sandboxs(host_wd: String, bwrap_wd: String) -> (Host, BubbleWrap)
         .map(String::from)
         .collect(),
     );
-    (host_sb, bwrap_sb)
+    let ns_sb = Namespace {
+        path: wd.to_string(),
+        root: wd.to_string() + "_root",
+        ro_binds: [
+            "/lib",
+            "/lib64",
+            "/usr",
+            "/bin",
+            "/sbin",
+            "/etc/alternatives",
+            "/etc/ssl/certs",
+            "/usr/share/ca-certificates",
+            "/etc/machine-id",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect(),
+        hostname: "RESTRICTED".to_string(),
+    };
+    let overlay_sb = rootfs.map(|rootfs| Overlay::new(wd.to_string(), rootfs.to_string()));
+    (host_sb, bwrap_sb, ns_sb, overlay_sb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    #[test]
+    fn paths_must_stay_beneath_the_root() {
+        let root = std::env::temp_dir().join(format!("freeitw_test_root_{}", std::process::id()));
+        fs::create_dir_all(root.join("wd")).unwrap();
+        std::os::unix::fs::symlink("/etc", root.join("etc")).unwrap();
+        let under = |p: &str| is_beneath(&format!("{}/{p}", root.display()), &root);
+
+        let checks = [under("wd"), under("wd/new/dir"), under("wd/../../etc"), under("etc/x")];
+        assert!(!is_beneath("relative/wd", &root));
+        assert!(!is_beneath("/etc", &root));
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(checks, [true, true, false, false]);
+    }
+
+    #[test]
+    fn scratch_is_private_and_fresh() {
+        let scratch = Scratch::new().unwrap();
+        let other = Scratch::new().unwrap();
+        assert_ne!(scratch.path, other.path);
+        let metadata = fs::symlink_metadata(&scratch.path).unwrap();
+        assert!(metadata.is_dir());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o700);
+    }
+
+    #[test]
+    fn scratch_is_removed_with_an_overlay_work_dir() {
+        let scratch = Scratch::new().unwrap();
+        let work = Path::new(&scratch.path).join("work/work");
+        fs::create_dir_all(work.join("index")).unwrap();
+        fs::set_permissions(&work, fs::Permissions::from_mode(0o000)).unwrap();
+        let path = scratch.path.clone();
+        std::mem::drop(scratch);
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn bwrap_lambdas_always_die_with_bwrap() {
+        let bwrap = BubbleWrap::new("/srv/lambdas", vec!["--new-session".to_string()]);
+        let (cmd, _scope) =
+            bwrap.prepare_spawn("hello.sh", &["hi"], &[], &Options::default()).unwrap();
+        let args: Vec<_> = cmd.as_std().get_args().filter_map(|a| a.to_str()).collect();
+        let program = args.iter().position(|a| *a == "--").unwrap();
+        assert!(args[..program].contains(&"--die-with-parent"), "{args:?}");
+        assert_eq!(args[program..], ["--", "/srv/lambdas/hello.sh", "hi"]);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs /usr/bin/bwrap"]
+    async fn bwrap_runs_lambdas_of_a_directory_under_tmp() {
+        let wd = std::env::temp_dir().join(format!("freeitw_test_bwrap_{}", std::process::id()));
+        fs::create_dir_all(&wd).unwrap();
+        let wd = wd.to_string_lossy().into_owned();
+        let (_, bwrap, _, _) = default_sandboxs(&wd, None);
+        let sandbox = Sandbox::from(SandboxKind::BubbleWrap(bwrap));
+        sandbox.injest(b"#!/bin/sh\necho \"$1 from $(pwd)\"\n", "hello.sh").unwrap();
+
+        let (mut cmd, scope) = sandbox.prepare_spawn("hello.sh", &["hi"], &[]).unwrap();
+        let out = cmd.stdin(Stdio::null()).stdout(Stdio::piped()).output().await.unwrap();
+        std::mem::drop(scope);
+        fs::remove_dir_all(&wd).unwrap();
+
+        assert!(out.status.success(), "{out:?}");
+        assert_eq!(out.stdout, b"hi from /tmp\n");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs unprivileged user namespaces"]
+    async fn namespace_spawn_reports_exec_errors_without_waiting_for_the_exit() {
+        let wd = std::env::temp_dir().join(format!("freeitw_test_ns_{}", std::process::id()));
+        fs::create_dir_all(&wd).unwrap();
+        let wd = wd.to_string_lossy().into_owned();
+        let (_, _, namespace, _) = default_sandboxs(&wd, None);
+        let sandbox = Sandbox::from(SandboxKind::Namespace(namespace));
+        sandbox.injest(b"#!/bin/sh\nsleep 2; echo hi\n", "hello.sh").unwrap();
+
+        let spawn = |prg| {
+            let (mut cmd, scope) = sandbox.prepare_spawn(prg, &[], &[]).unwrap();
+            let child = cmd.stdin(Stdio::null()).stdout(Stdio::piped()).spawn();
+            (child, scope)
+        };
+        let (missing, _missing_scope) = spawn("missing.sh");
+        let started = std::time::Instant::now();
+        let (hello, _hello_scope) = spawn("hello.sh");
+        let spawned_in = started.elapsed();
+        let out = hello.unwrap().wait_with_output().await.unwrap();
+        fs::remove_dir_all(&wd).unwrap();
+        fs::remove_dir_all(wd + "_root").unwrap();
+
+        assert_eq!(out.stdout, b"hi\n");
+        assert!(spawned_in < std::time::Duration::from_secs(1), "{spawned_in:?}");
+        assert_eq!(missing.unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
 }
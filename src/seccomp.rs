@@ -0,0 +1,274 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::num::TryFromIntError;
+use tokio::process::Command;
+
+/// `AUDIT_ARCH_*` value of the syscalls the filter is compiled for
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Offsets in `struct seccomp_data`
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+/// Offset of the low half of the first syscall argument
+#[cfg(target_endian = "little")]
+const DATA_ARG0_LOW: u32 = 16;
+#[cfg(target_endian = "big")]
+const DATA_ARG0_LOW: u32 = 20;
+
+/// x32 syscalls numbers have this bit set on `x86_64`
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Named syscall filter profiles
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Fail with `EPERM` the syscalls letting code escape or attack the kernel:
+    /// tracing, mounts, namespaces, kernel modules and kexec, bpf, keyrings, personality...
+    DefaultDenyDangerous,
+}
+
+/// `clone` flags creating namespaces. `CLONE_NEWTIME` is only one for `clone3` and
+/// `unshare`, its bit is part of the exit signal of `clone`
+const CLONE_NAMESPACES: [libc::c_int; 7] = [
+    libc::CLONE_NEWNS,
+    libc::CLONE_NEWCGROUP,
+    libc::CLONE_NEWUTS,
+    libc::CLONE_NEWIPC,
+    libc::CLONE_NEWUSER,
+    libc::CLONE_NEWPID,
+    libc::CLONE_NEWNET,
+];
+
+impl Profile {
+    /// Syscalls failing with `EPERM`, by name and number
+    fn denied(self) -> Vec<(&'static str, libc::c_long)> {
+        match self {
+            Self::DefaultDenyDangerous => vec![
+                ("ptrace", libc::SYS_ptrace),
+                ("process_vm_readv", libc::SYS_process_vm_readv),
+                ("process_vm_writev", libc::SYS_process_vm_writev),
+                ("mount", libc::SYS_mount),
+                ("umount2", libc::SYS_umount2),
+                ("pivot_root", libc::SYS_pivot_root),
+                ("move_mount", libc::SYS_move_mount),
+                ("open_tree", libc::SYS_open_tree),
+                ("fsopen", libc::SYS_fsopen),
+                ("fsmount", libc::SYS_fsmount),
+                ("unshare", libc::SYS_unshare),
+                ("setns", libc::SYS_setns),
+                ("kexec_load", libc::SYS_kexec_load),
+                ("kexec_file_load", libc::SYS_kexec_file_load),
+                ("init_module", libc::SYS_init_module),
+                ("finit_module", libc::SYS_finit_module),
+                ("delete_module", libc::SYS_delete_module),
+                ("bpf", libc::SYS_bpf),
+                ("perf_event_open", libc::SYS_perf_event_open),
+                ("userfaultfd", libc::SYS_userfaultfd),
+                ("keyctl", libc::SYS_keyctl),
+                ("add_key", libc::SYS_add_key),
+                ("request_key", libc::SYS_request_key),
+                ("personality", libc::SYS_personality),
+                ("reboot", libc::SYS_reboot),
+                ("swapon", libc::SYS_swapon),
+                ("swapoff", libc::SYS_swapoff),
+                ("acct", libc::SYS_acct),
+                ("syslog", libc::SYS_syslog),
+                ("open_by_handle_at", libc::SYS_open_by_handle_at),
+            ],
+        }
+    }
+
+    /// Names of the syscalls failing with `EPERM`, for OCI runtimes
+    #[must_use]
+    pub fn denied_names(self) -> Vec<&'static str> {
+        self.denied().into_iter().map(|(name, _)| name).collect()
+    }
+
+    /// `clone` flags failing with `EPERM`, `clone3` then fails with `ENOSYS`:
+    /// a filter can't read its flags, libc falls back to `clone`
+    #[must_use]
+    pub fn denied_clone_flags(self) -> Vec<u32> {
+        match self {
+            Self::DefaultDenyDangerous => {
+                CLONE_NAMESPACES.iter().filter_map(|f| u32::try_from(*f).ok()).collect()
+            }
+        }
+    }
+
+    /// Compile the profile to a classic BPF program
+    /// # Errors
+    ///     when an instruction field doesn't fit
+    pub fn compile(self) -> io::Result<Vec<libc::sock_filter>> {
+        let invalid = |e: TryFromIntError| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let jump = |code: u32, k: u32, jt: u8, jf: u8| -> io::Result<libc::sock_filter> {
+            Ok(libc::sock_filter { code: u16::try_from(code).map_err(invalid)?, jt, jf, k })
+        };
+        let stmt = |code: u32, k: u32| jump(code, k, 0, 0);
+        let deny = libc::SECCOMP_RET_ERRNO | u32::try_from(libc::EPERM).map_err(invalid)?;
+
+        let mut program = vec![
+            // Kill syscalls of another architecture, their numbers differ
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARCH)?,
+            jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, AUDIT_ARCH, 1, 0)?,
+            stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_KILL_PROCESS)?,
+            stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_NR)?,
+        ];
+        #[cfg(target_arch = "x86_64")]
+        program.extend([
+            jump(libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K, X32_SYSCALL_BIT, 0, 1)?,
+            stmt(libc::BPF_RET | libc::BPF_K, deny)?,
+        ]);
+        for (_, nr) in self.denied() {
+            let nr = u32::try_from(nr).map_err(invalid)?;
+            program.extend([
+                jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, nr, 0, 1)?,
+                stmt(libc::BPF_RET | libc::BPF_K, deny)?,
+            ]);
+        }
+        let clone_flags = self.denied_clone_flags().into_iter().fold(0, |mask, f| mask | f);
+        if clone_flags != 0 {
+            let clone = u32::try_from(libc::SYS_clone).map_err(invalid)?;
+            let clone3 = u32::try_from(libc::SYS_clone3).map_err(invalid)?;
+            let no_clone3 =
+                libc::SECCOMP_RET_ERRNO | u32::try_from(libc::ENOSYS).map_err(invalid)?;
+            program.extend([
+                jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, clone3, 0, 1)?,
+                stmt(libc::BPF_RET | libc::BPF_K, no_clone3)?,
+                // Any of the flags denies the clone, which is allowed otherwise
+                jump(libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K, clone, 0, 4)?,
+                stmt(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, DATA_ARG0_LOW)?,
+                jump(libc::BPF_JMP | libc::BPF_JSET | libc::BPF_K, clone_flags, 0, 1)?,
+                stmt(libc::BPF_RET | libc::BPF_K, deny)?,
+                stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW)?,
+            ]);
+        }
+        program.push(stmt(libc::BPF_RET | libc::BPF_K, libc::SECCOMP_RET_ALLOW)?);
+        match u16::try_from(program.len()) {
+            Ok(_) => Ok(program),
+            Err(e) => Err(invalid(e)),
+        }
+    }
+
+    /// The compiled program as bytes, the format of bwrap `--seccomp`
+    /// # Errors
+    ///     see `compile`
+    pub fn to_bytes(self) -> io::Result<Vec<u8>> {
+        Ok(self
+            .compile()?
+            .iter()
+            .flat_map(|f| {
+                let mut bytes = Vec::with_capacity(8);
+                bytes.extend(f.code.to_ne_bytes());
+                bytes.extend([f.jt, f.jf]);
+                bytes.extend(f.k.to_ne_bytes());
+                bytes
+            })
+            .collect())
+    }
+}
+
+/// Install the filter in the spawned process right before exec
+/// # Errors
+///     when the profile doesn't compile
+pub fn apply_on_exec(cmd: &mut Command, profile: Profile) -> io::Result<()> {
+    let program = profile.compile()?;
+    let len =
+        u16::try_from(program.len()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: the closure only calls prctl(2) which is async-signal-safe,
+    // the program is allocated by the parent and moved into the closure
+    #[allow(unsafe_code, reason = "the filter must be installed between fork and exec")]
+    let _ = unsafe {
+        cmd.pre_exec(move || {
+            let prog = libc::sock_fprog { len, filter: program.as_ptr().cast_mut() };
+            // Required to install a filter without CAP_SYS_ADMIN
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) < 0
+                || libc::prctl(libc::PR_SET_SECCOMP, libc::SECCOMP_MODE_FILTER, &raw const prog) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compiles_a_check_and_a_return_per_denied_syscall() {
+        let profile = Profile::DefaultDenyDangerous;
+        let program = profile.compile().unwrap();
+        let arch_checks = if cfg!(target_arch = "x86_64") { 6 } else { 4 };
+        let clone_checks = 7;
+        assert_eq!(program.len(), arch_checks + 2 * profile.denied().len() + clone_checks + 1);
+        assert_eq!(program[0].k, DATA_ARCH);
+        assert_eq!(program[1].k, AUDIT_ARCH);
+        let flags = program.iter().find(|f| f.code == 0x45).map(|f| f.k);
+        assert_eq!(flags, Some(0x7e02_0000));
+        assert_eq!(program.last().map(|f| f.k), Some(libc::SECCOMP_RET_ALLOW));
+        assert_eq!(profile.to_bytes().unwrap().len(), 8 * program.len());
+    }
+
+    /// Fail unless the raw syscall `nr` with `args` fails with `errno`,
+    /// a child it forks exits right away
+    /// # Safety
+    ///     async-signal-safe, only does syscalls
+    #[allow(unsafe_code, reason = "raw syscalls between fork and exec")]
+    unsafe fn expect_errno(
+        nr: libc::c_long,
+        args: [libc::c_long; 2],
+        errno: i32,
+    ) -> io::Result<()> {
+        let ret = libc::syscall(nr, args[0], args[1], 0, 0, 0);
+        match ret {
+            0 if nr == libc::SYS_clone => libc::_exit(0),
+            -1 if io::Error::last_os_error().raw_os_error() == Some(errno) => Ok(()),
+            -1 => Err(io::Error::last_os_error()),
+            child => {
+                if nr == libc::SYS_clone {
+                    let _ = libc::waitpid(
+                        libc::pid_t::try_from(child).unwrap_or_default(),
+                        std::ptr::null_mut(),
+                        0,
+                    );
+                }
+                Err(io::Error::other(format!("syscall {nr} succeeded")))
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn namespaces_cant_be_created() {
+        let sigchld = libc::c_long::from(libc::SIGCHLD);
+        let new_user = libc::c_long::from(libc::CLONE_NEWUSER);
+        let mut cmd = Command::new("/bin/true");
+        apply_on_exec(&mut cmd, Profile::DefaultDenyDangerous).unwrap();
+        // SAFETY: only raw syscalls after the filter is installed
+        #[allow(unsafe_code, reason = "the syscalls must be tried in the filtered child")]
+        let _ = unsafe {
+            cmd.pre_exec(move || {
+                expect_errno(libc::SYS_unshare, [new_user, 0], libc::EPERM)?;
+                expect_errno(libc::SYS_clone, [new_user | sigchld, 0], libc::EPERM)?;
+                expect_errno(libc::SYS_clone3, [0, 0], libc::ENOSYS)?;
+                // A plain fork still works
+                match libc::syscall(libc::SYS_clone, sigchld, 0, 0, 0, 0) {
+                    0 => libc::_exit(0),
+                    -1 => Err(io::Error::last_os_error()),
+                    child => {
+                        let child = libc::pid_t::try_from(child).unwrap_or_default();
+                        let _ = libc::waitpid(child, std::ptr::null_mut(), 0);
+                        Ok(())
+                    }
+                }
+            })
+        };
+        let status = cmd.status().await.unwrap();
+        assert!(status.success());
+    }
+}
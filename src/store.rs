@@ -0,0 +1,264 @@
+use anyhow::{bail, Context, Result};
+use enum_dispatch::enum_dispatch;
+use log::warn;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write as _;
+use std::os::unix::fs::{DirBuilderExt as _, MetadataExt as _};
+use std::path::PathBuf;
+
+/// Collection of the lambdas
+pub const LAMBDAS: &str = "lambdas";
+/// Collection of the sandboxes
+pub const SANDBOXS: &str = "sandboxs";
+
+/// Kind of storage backing the registry
+#[allow(clippy::module_name_repetitions, reason = "Kind suffix names the dispatch enum")]
+#[derive(Debug)]
+#[enum_dispatch]
+pub enum StoreKind {
+    /// Nothing survives a restart
+    Memory(Memory),
+    /// A directory of JSON documents
+    Directory(Directory),
+}
+
+/// Storage of JSON documents by collection and key, written through on each change
+#[enum_dispatch(StoreKind)]
+pub trait Trait {
+    /// Load all the documents of a collection
+    /// # Errors
+    ///     when the storage can't be read
+    fn load(&self, collection: &str) -> Result<Vec<(String, Value)>>;
+
+    /// Create or replace a document
+    /// # Errors
+    ///     when the storage can't be written
+    fn save(&self, collection: &str, key: &str, document: &Value) -> Result<()>;
+
+    /// Delete a document, deleting a missing one is fine
+    /// # Errors
+    ///     when the storage can't be written
+    fn delete(&self, collection: &str, key: &str) -> Result<()>;
+}
+
+/// In-memory only registry
+#[derive(Debug)]
+pub struct Memory;
+
+impl Trait for Memory {
+    fn load(&self, _collection: &str) -> Result<Vec<(String, Value)>> {
+        Ok(Vec::new())
+    }
+
+    fn save(&self, _collection: &str, _key: &str, _document: &Value) -> Result<()> {
+        Ok(())
+    }
+
+    fn delete(&self, _collection: &str, _key: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Registry stored as `<path>/<collection>/<key>.json` files,
+/// each replaced atomically by renaming a synced temporary file over it
+#[derive(Debug)]
+pub struct Directory {
+    /// Root directory
+    pub path: PathBuf,
+}
+
+impl Directory {
+    /// Default root, `$XDG_STATE_HOME/freeitw` or else `$HOME/.local/state/freeitw`
+    pub fn default_path() -> Option<PathBuf> {
+        let state_home = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .filter(|p| p.is_absolute())
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })?;
+        Some(state_home.join("freeitw"))
+    }
+
+    /// Registry in `path`, created private to the server user when missing
+    /// # Errors
+    ///     when it can't be created or other users can write in it
+    pub fn open(path: PathBuf) -> Result<Self> {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(&path)
+            .with_context(|| format!("creating {}", path.display()))?;
+        let mode = fs::metadata(&path)?.mode();
+        if mode & 0o022 != 0 {
+            bail!("{} is writable by other users", path.display());
+        }
+        if mode & 0o077 != 0 {
+            warn!("{} is readable by other users", path.display());
+        }
+        Ok(Self { path })
+    }
+
+    /// Directory of a collection
+    fn collection(&self, collection: &str) -> PathBuf {
+        self.path.join(collection)
+    }
+
+    /// File name of a non empty key, bytes that aren't safe in a file name are escaped as `%XX`
+    fn file_name(key: &str) -> String {
+        let escaped: String = key
+            .bytes()
+            .enumerate()
+            .map(|(i, b)| match b {
+                b'.' if i == 0 => "%2E".to_string(),
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' | b'.' => {
+                    char::from(b).to_string()
+                }
+                _ => format!("%{b:02X}"),
+            })
+            .collect();
+        escaped + ".json"
+    }
+
+    /// Key of a file name, `None` for files which aren't documents
+    fn key(file_name: &str) -> Option<String> {
+        let escaped = file_name.strip_suffix(".json").filter(|n| !n.starts_with('.'))?;
+        let mut bytes = Vec::with_capacity(escaped.len());
+        let mut rest = escaped.as_bytes();
+        while let Some((&b, tail)) = rest.split_first() {
+            rest = tail;
+            if b != b'%' {
+                bytes.push(b);
+                continue;
+            }
+            let hex = std::str::from_utf8(rest.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &rest[2..];
+        }
+        String::from_utf8(bytes).ok()
+    }
+}
+
+impl Trait for Directory {
+    fn load(&self, collection: &str) -> Result<Vec<(String, Value)>> {
+        let dir = self.collection(collection);
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+        let mut documents = Vec::new();
+        for entry in fs::read_dir(&dir).with_context(|| format!("reading {}", dir.display()))? {
+            let path = entry?.path();
+            let Some(key) = path.file_name().and_then(|n| n.to_str()).and_then(Self::key) else {
+                continue;
+            };
+            let content = fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+            let document = serde_json::from_slice(&content)
+                .with_context(|| format!("parsing {}", path.display()))?;
+            documents.push((key, document));
+        }
+        Ok(documents)
+    }
+
+    fn save(&self, collection: &str, key: &str, document: &Value) -> Result<()> {
+        let dir = self.collection(collection);
+        fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        let name = Self::file_name(key);
+        let path = dir.join(&name);
+        let tmp = dir.join(format!(".{name}.tmp"));
+
+        let mut file = File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?;
+        serde_json::to_writer_pretty(&mut file, document)?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path).with_context(|| format!("renaming to {}", path.display()))?;
+        // Make the rename itself durable
+        File::open(&dir)?.sync_all()?;
+        Ok(())
+    }
+
+    fn delete(&self, collection: &str, key: &str) -> Result<()> {
+        let dir = self.collection(collection);
+        let path = dir.join(Self::file_name(key));
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            deleted => deleted.with_context(|| format!("deleting {}", path.display()))?,
+        }
+        File::open(&dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Load and deserialize all the documents of a collection, invalid ones are skipped
+/// # Errors
+///     when the storage can't be read
+pub fn load_all<T: DeserializeOwned>(
+    store: &StoreKind,
+    collection: &str,
+) -> Result<HashMap<String, T>> {
+    let mut loaded = HashMap::new();
+    for (key, document) in store.load(collection)? {
+        match serde_json::from_value(document) {
+            Ok(value) => {
+                let _ = loaded.insert(key, value);
+            }
+            Err(e) => warn!("Skipping invalid stored {collection} {key}: {e}"),
+        }
+    }
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_round_trip_to_their_keys() {
+        for key in ["plain", ".hidden", "..", "50%", "%2E", "a/b", "a.b", "é ü"] {
+            let name = Directory::file_name(key);
+            assert!(!name.starts_with('.'), "{name}");
+            assert!(!name.contains('/'), "{name}");
+            assert_eq!(Directory::key(&name).as_deref(), Some(key), "{name}");
+        }
+        assert_eq!(Directory::file_name(".a.b"), "%2Ea.b.json");
+        assert_eq!(Directory::file_name("50%"), "50%25.json");
+    }
+
+    #[test]
+    fn directories_are_opened_private() {
+        let path = std::env::temp_dir().join(format!("freeitw_test_open_{}", std::process::id()));
+        let nested = path.join("state/freeitw");
+        let store = Directory::open(nested.clone()).unwrap();
+        assert_eq!(store.path, nested);
+        assert_eq!(fs::metadata(&nested).unwrap().mode() & 0o777, 0o700);
+        fs::set_permissions(&nested, std::os::unix::fs::PermissionsExt::from_mode(0o777)).unwrap();
+        assert!(Directory::open(nested).is_err());
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn key_skips_files_which_are_not_documents() {
+        assert_eq!(Directory::key(".hidden.json.tmp"), None);
+        assert_eq!(Directory::key(".hidden.json"), None);
+        assert_eq!(Directory::key("notes.txt"), None);
+        assert_eq!(Directory::key("bad%2.json"), None);
+        assert_eq!(Directory::key("bad%zz.json"), None);
+        assert_eq!(Directory::key("bad%FF.json"), None);
+    }
+
+    #[test]
+    fn directory_saves_loads_and_deletes_documents() {
+        let path = std::env::temp_dir().join(format!("freeitw_test_store_{}", std::process::id()));
+        let store = Directory { path: path.clone() };
+        let document = serde_json::json!({"a": 1});
+        store.save("lambdas", ".x/y", &document).unwrap();
+        store.save("lambdas", ".x/y", &document).unwrap();
+        assert_eq!(store.load("lambdas").unwrap(), vec![(".x/y".to_string(), document)]);
+        store.delete("lambdas", ".x/y").unwrap();
+        store.delete("lambdas", ".x/y").unwrap();
+        assert!(store.load("lambdas").unwrap().is_empty());
+        assert!(store.load("missing").unwrap().is_empty());
+        fs::remove_dir_all(&path).unwrap();
+    }
+}